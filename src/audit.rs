@@ -0,0 +1,276 @@
+// Comparing a system set up by the classic bash script with what setup-linux would do, without
+// changing anything, so migrating isn't a leap of faith. Rules are compared by what they do (the
+// HMARK parameters, which mark SNATs to which range, how MSS is clamped) rather than as text, as
+// the script and iptables -S spell the same rule differently.
+
+use anyhow::Context;
+use clap::Parser;
+use cmd_lib::run_fun;
+
+use crate::nat::{self, FwRule};
+use crate::NatArgs;
+use crate::{iface, AddrArgs};
+
+#[derive(Parser)]
+pub struct AuditLegacy {
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long = "tun",
+        default_value = "ip4tun0",
+        help = "Tunnel interface the script created"
+    )]
+    tun_dev: String,
+    #[command(flatten)]
+    nat: NatArgs,
+}
+
+#[derive(PartialEq)]
+struct Hmark {
+    matches: Vec<String>,
+    tuple: String,
+    modulus: u64,
+    offset: u64,
+    rnd: u64,
+}
+
+impl std::fmt::Display for Hmark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for m in &self.matches {
+            write!(f, "{m} ")?;
+        }
+        write!(
+            f,
+            "tuple {} mod {} offset {:#x} rnd {:#x}",
+            self.tuple, self.modulus, self.offset, self.rnd
+        )
+    }
+}
+
+#[derive(PartialEq)]
+struct Snat {
+    proto: String,
+    mark: Option<u64>,
+    to: String,
+}
+
+impl std::fmt::Display for Snat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.proto)?;
+        if let Some(mark) = self.mark {
+            write!(f, " mark {mark:#x}")?;
+        }
+        write!(f, " -> {}", self.to)
+    }
+}
+
+// iptables -S prints numbers in decimal or hex depending on the match
+fn number(s: &str) -> u64 {
+    let s = s.split('/').next().unwrap_or_default();
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).unwrap_or(0),
+        None => s.parse().unwrap_or(0),
+    }
+}
+
+// The value after a flag, as in "--hmark-mod 15"
+fn value<'a>(tokens: &[&'a str], flag: &str) -> Option<&'a str> {
+    let i = tokens.iter().position(|t| *t == flag)?;
+    tokens.get(i + 1).copied()
+}
+
+// A rule's matches without the "-m tcp" iptables -S adds after "-p tcp"
+fn matches(tokens: &[&str]) -> Vec<String> {
+    let end = tokens
+        .iter()
+        .position(|t| *t == "-j")
+        .unwrap_or(tokens.len());
+    let proto = value(tokens, "-p");
+    let mut out = vec![];
+    let mut i = 0;
+    while i < end {
+        if tokens[i] == "-m" && tokens.get(i + 1).copied() == proto {
+            i += 2;
+            continue;
+        }
+        out.push(tokens[i].to_string());
+        i += 1;
+    }
+    out
+}
+
+fn show(table: &str, chain: &str) -> anyhow::Result<Vec<Vec<String>>> {
+    let out = run_fun!(iptables -t $table -S $chain)
+        .with_context(|| format!("could not list {table} {chain}"))?;
+    // Skipping "-A CHAIN"; the "-P CHAIN ACCEPT" policy line doesn't start with -A
+    Ok(out
+        .lines()
+        .filter_map(|l| l.strip_prefix(&format!("-A {chain} ")))
+        .map(|l| l.split_whitespace().map(String::from).collect())
+        .collect())
+}
+
+impl AuditLegacy {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let plan = self.nat.plan(&data.port_ranges)?;
+        let tun_dev = &self.tun_dev;
+
+        let mut want_hmark = vec![];
+        let mut want_local_hmark = vec![];
+        let mut want_snat = vec![];
+        for rule in self.nat.rules(&plan, data.ipv4_addr) {
+            match rule {
+                FwRule::Hmark { local, .. } => {
+                    let (_, _, args) = rule.iptables(tun_dev);
+                    let tokens: Vec<_> = args.iter().map(String::as_str).collect();
+                    if local {
+                        want_local_hmark.push(hmark(&tokens));
+                    } else {
+                        want_hmark.push(hmark(&tokens));
+                    }
+                }
+                FwRule::Snat {
+                    proto,
+                    mark,
+                    ipv4_addr,
+                    range,
+                } => want_snat.push(Snat {
+                    proto: proto.to_string(),
+                    mark: mark.map(|m| m as u64),
+                    to: nat::snat_to(ipv4_addr, range),
+                }),
+                FwRule::DnsRedirect { .. } | FwRule::ClampMss { .. } => {}
+            }
+        }
+        let hmarks = |chain| -> anyhow::Result<Vec<Hmark>> {
+            Ok(show("mangle", chain)?
+                .iter()
+                .map(|rule| rule.iter().map(String::as_str).collect::<Vec<_>>())
+                .filter(|tokens| value(tokens, "-j") == Some("HMARK"))
+                .map(|tokens| hmark(&tokens))
+                .collect())
+        };
+        let have_hmark = hmarks("PREROUTING")?;
+        let have_local_hmark = hmarks("OUTPUT")?;
+        let mut have_snat = vec![];
+        for rule in show("nat", "POSTROUTING")? {
+            let tokens: Vec<_> = rule.iter().map(String::as_str).collect();
+            if value(&tokens, "-j") == Some("SNAT") {
+                have_snat.push(Snat {
+                    proto: value(&tokens, "-p").unwrap_or("all").to_string(),
+                    mark: value(&tokens, "--mark").map(number),
+                    to: value(&tokens, "--to-source")
+                        .unwrap_or_default()
+                        .to_string(),
+                });
+            }
+        }
+        let have_mss: Vec<_> = ["FORWARD", "POSTROUTING"]
+            .into_iter()
+            .map(|chain| Ok((chain, show("mangle", chain)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .into_iter()
+            .flat_map(|(chain, rules)| {
+                rules.into_iter().filter_map(move |rule| {
+                    let i = rule.iter().position(|t| t == "TCPMSS")?;
+                    Some(format!("{chain} {}", rule[i + 1..].join(" ")))
+                })
+            })
+            .collect();
+        let want_mss = vec!["FORWARD --clamp-mss-to-pmtu".to_string()];
+
+        let mut differences = 0;
+        let mut report = |what: &str, same: bool, have: String, want: String| {
+            if same {
+                println!("same    {what}");
+            } else {
+                differences += 1;
+                println!("differs {what}\n  legacy: {have}\n  ours:   {want}");
+            }
+        };
+        // HMARK rules overwrite each other's marks, so here order does matter
+        let list = |rules: &[Hmark]| {
+            let rules: Vec<_> = rules.iter().map(Hmark::to_string).collect();
+            list_or_none(&rules)
+        };
+        report(
+            "HMARK",
+            have_hmark == want_hmark,
+            list(&have_hmark),
+            list(&want_hmark),
+        );
+        report(
+            "HMARK for the router's own traffic",
+            have_local_hmark == want_local_hmark,
+            list(&have_local_hmark),
+            list(&want_local_hmark),
+        );
+        // SNAT rules differ in mark or protocol, so never overlap and their order doesn't matter
+        let missing: Vec<_> = want_snat
+            .iter()
+            .filter(|r| !have_snat.contains(r))
+            .map(Snat::to_string)
+            .collect();
+        let extra: Vec<_> = have_snat
+            .iter()
+            .filter(|r| !want_snat.contains(r))
+            .map(Snat::to_string)
+            .collect();
+        report(
+            &format!("SNAT ({} rules)", have_snat.len()),
+            missing.is_empty() && extra.is_empty(),
+            format!("extra {}", list_or_none(&extra)),
+            format!("missing from legacy {}", list_or_none(&missing)),
+        );
+        report(
+            "MSS clamping",
+            have_mss == want_mss,
+            have_mss.join(", "),
+            want_mss.join(", "),
+        );
+        let tunnel = iface::ip6_tunnel(tun_dev)?;
+        let want_tunnel = format!("ip/ipv6 remote {} local {}", data.br_addr, data.edge_addr);
+        report(
+            &format!("tunnel {tun_dev}"),
+            tunnel.as_ref().is_some_and(|t| {
+                t.mode == "ip/ipv6"
+                    && t.remote == Some(data.br_addr)
+                    && t.local == Some(data.edge_addr)
+            }),
+            tunnel.map_or("missing".to_string(), |t| t.to_string()),
+            want_tunnel,
+        );
+        let mtu = std::fs::read_to_string(format!("/sys/class/net/{tun_dev}/mtu"))
+            .unwrap_or_else(|_| "missing".to_string());
+        report(
+            &format!("{tun_dev} MTU"),
+            mtu.trim() == "1460",
+            mtu.trim().to_string(),
+            "1460".to_string(),
+        );
+
+        println!("{differences} differences");
+        Ok(())
+    }
+}
+
+fn list_or_none(items: &[String]) -> String {
+    if items.is_empty() {
+        "none".to_string()
+    } else {
+        items.join(", ")
+    }
+}
+
+fn hmark(tokens: &[&str]) -> Hmark {
+    Hmark {
+        matches: matches(tokens),
+        tuple: value(tokens, "--hmark-tuple")
+            .unwrap_or_default()
+            .to_string(),
+        modulus: value(tokens, "--hmark-mod").map_or(0, number),
+        offset: value(tokens, "--hmark-offset").map_or(0, number),
+        rnd: value(tokens, "--hmark-rnd").map_or(0, number),
+    }
+}
@@ -0,0 +1,348 @@
+// Reading what the ISP handed out over DHCPv6, from the local client's saved state. On a router
+// the delegated prefix is what MAP-E is calculated from, rather than whatever SLAAC put on the WAN.
+//
+// dhcpcd saves the last Reply as the raw message (e.g. /var/lib/dhcpcd/eth0.lease6). The
+// delegated prefix is always in it, and on ISPs that provision MAP-E over DHCPv6 (RFC 7598) so are
+// the mapping rule and BR, which then take precedence over our own table. OpenWrt's odhcp6c
+// keeps no file of its own but reports to netifd, whose `ifstatus` JSON has the prefix.
+
+use anyhow::{bail, Context};
+
+use crate::rules::{CeLayout, Confidence, Rule};
+
+const OPTION_DNS_SERVERS: u16 = 23;
+const OPTION_IA_PD: u16 = 25;
+const OPTION_IAPREFIX: u16 = 26;
+const OPTION_S46_RULE: u16 = 89;
+const OPTION_S46_BR: u16 = 90;
+const OPTION_S46_PORTPARAMS: u16 = 93;
+const OPTION_S46_CONT_MAPE: u16 = 94;
+
+// RFC 7597's default when the rule doesn't say
+const DEFAULT_PSID_OFFSET: u8 = 6;
+
+#[derive(Debug, Default)]
+pub struct Lease {
+    pub prefixes: Vec<ipnet::Ipv6Net>,
+    // Basic mapping rules, i.e. ones with the FMR flag or not; we only need the one covering us
+    pub rules: Vec<v6plus_tun::Bmr>,
+    // The ones with the FMR flag, for reaching CEs under them directly rather than through the BR
+    pub fmrs: Vec<v6plus_tun::Bmr>,
+    pub br: Option<std::net::Ipv6Addr>,
+    // The NGN's own resolvers, on NTT lines
+    pub dns: Vec<std::net::Ipv6Addr>,
+}
+
+// (code, data) for each option in a buffer of options
+fn options(mut buf: &[u8]) -> anyhow::Result<Vec<(u16, &[u8])>> {
+    let mut out = vec![];
+    while !buf.is_empty() {
+        if buf.len() < 4 {
+            bail!("truncated option header");
+        }
+        let code = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let Some(data) = buf.get(4..4 + len) else {
+            bail!("option {code} is truncated");
+        };
+        out.push((code, data));
+        buf = &buf[4 + len..];
+    }
+    Ok(out)
+}
+
+// The only lease6 file in dhcpcd's directory. With several (one per interface) it's ambiguous which
+// one is the WAN.
+fn dhcpcd_lease() -> anyhow::Result<std::path::PathBuf> {
+    let mut leases = vec![];
+    for dir in DHCPCD_DIRS {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "lease6") {
+                leases.push(path);
+            }
+        }
+    }
+    match leases.as_slice() {
+        [] => bail!("no dhcpcd lease6 file in {}", DHCPCD_DIRS.join(", ")),
+        [lease] => Ok(lease.clone()),
+        _ => bail!(
+            "several dhcpcd leases, pick one with dhcpcd:PATH: {}",
+            leases
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+fn addr_from(bytes: &[u8]) -> std::net::Ipv6Addr {
+    let mut octets = [0; 16];
+    octets[..bytes.len()].copy_from_slice(bytes);
+    std::net::Ipv6Addr::from(octets)
+}
+
+// Where dhcpcd keeps leases, depending on version and distro
+const DHCPCD_DIRS: &[&str] = &["/var/lib/dhcpcd", "/var/db/dhcpcd", "/var/lib/dhcpcd5"];
+
+impl Lease {
+    // A client name, optionally with ":PATH" (dhcpcd's lease file, or saved `ifstatus` output for
+    // odhcp6c), or just a dhcpcd lease file
+    pub fn from_spec(spec: &str) -> anyhow::Result<Lease> {
+        let (client, path) = match spec.split_once(':') {
+            Some((client, path)) => (client, Some(path)),
+            None => (spec, None),
+        };
+        match (client, path) {
+            ("dhcpcd", Some(path)) => Lease::load(path.as_ref()),
+            ("dhcpcd", None) => Lease::load(&dhcpcd_lease()?),
+            ("odhcp6c", Some(path)) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read {path}"))?;
+                Lease::from_ifstatus(&text).with_context(|| format!("{path} is not ifstatus output"))
+            }
+            ("odhcp6c", None) => {
+                let out = std::process::Command::new("ifstatus")
+                    .arg("wan6")
+                    .output()
+                    .context("could not run ifstatus wan6")?;
+                if !out.status.success() {
+                    bail!("ifstatus wan6 failed, is this OpenWrt?");
+                }
+                Lease::from_ifstatus(&String::from_utf8_lossy(&out.stdout))
+                    .context("could not parse ifstatus wan6")
+            }
+            ("wide-dhcpv6", _) => bail!(
+                "wide-dhcpv6's dhcp6c doesn't save its lease; use --from-interface with the interface its prefix-interface assigns the prefix to"
+            ),
+            _ => Lease::load(spec.as_ref()),
+        }
+    }
+
+    // netifd's view of the interface, as printed by `ifstatus wan6`, with e.g.
+    // "ipv6-prefix": [ { "address": "240b:10:1234:5600::", "mask": 56, ... } ]
+    fn from_ifstatus(text: &str) -> anyhow::Result<Lease> {
+        let status: serde_json::Value = serde_json::from_str(text)?;
+        let mut lease = Lease::default();
+        for prefix in status["ipv6-prefix"].as_array().into_iter().flatten() {
+            let (Some(addr), Some(len)) = (prefix["address"].as_str(), prefix["mask"].as_u64())
+            else {
+                bail!("malformed ipv6-prefix entry {prefix}");
+            };
+            lease
+                .prefixes
+                .push(ipnet::Ipv6Net::new(addr.parse()?, len.try_into()?)?);
+        }
+        Ok(lease)
+    }
+
+    pub fn load(path: &std::path::Path) -> anyhow::Result<Lease> {
+        let msg =
+            std::fs::read(path).with_context(|| format!("could not read {}", path.display()))?;
+        Lease::parse(&msg).with_context(|| format!("{} is not a DHCPv6 message", path.display()))
+    }
+
+    // The address to calculate from: the start of the (first) delegated prefix, as the rules only
+    // look at the prefix bits anyway
+    pub fn addr(&self) -> anyhow::Result<std::net::Ipv6Addr> {
+        match self.prefixes.first() {
+            Some(prefix) => Ok(prefix.network()),
+            None => bail!("no delegated prefix in the lease"),
+        }
+    }
+
+    // The ISP's rule for our delegated prefix, or None if it doesn't provision MAP-E this way
+    pub fn rule(&self) -> anyhow::Result<Option<Rule>> {
+        if self.rules.is_empty() {
+            return Ok(None);
+        }
+        self.rule_for(self.addr()?)
+    }
+
+    // The same for an address from elsewhere, as an Information-Request's Reply has no prefix
+    pub fn rule_for(&self, addr: std::net::Ipv6Addr) -> anyhow::Result<Option<Rule>> {
+        if self.rules.is_empty() {
+            return Ok(None);
+        }
+        let Some(bmr) = self.rules.iter().find(|r| r.contains(addr)) else {
+            bail!("none of the DHCPv6 server's MAP-E rules cover {addr}");
+        };
+        Ok(Some(Rule {
+            bmr: *bmr,
+            br_addr: self
+                .br
+                .context("the DHCPv6 server sent MAP-E rules, but no BR")?,
+            ce_layout: CeLayout::Rfc7597,
+            source: "DHCPv6",
+            // It's what the BR itself is configured with
+            confidence: Confidence::Confirmed,
+        }))
+    }
+
+    // A whole DHCPv6 message: type, 3 bytes of transaction ID, then options
+    pub fn parse(msg: &[u8]) -> anyhow::Result<Lease> {
+        if msg.len() < 4 {
+            bail!("too short");
+        }
+        let mut lease = Lease::default();
+        for (code, data) in options(&msg[4..])? {
+            match code {
+                OPTION_IA_PD => lease.parse_ia_pd(data)?,
+                OPTION_S46_CONT_MAPE => lease.parse_mape(data)?,
+                OPTION_DNS_SERVERS => lease.dns.extend(data.chunks_exact(16).map(addr_from)),
+                _ => {}
+            }
+        }
+        Ok(lease)
+    }
+
+    fn parse_ia_pd(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        // IAID, T1 and T2 come first
+        let Some(opts) = data.get(12..) else {
+            bail!("IA_PD is truncated");
+        };
+        for (code, data) in options(opts)? {
+            if code != OPTION_IAPREFIX {
+                continue;
+            }
+            // preferred and valid lifetimes, prefix length, prefix
+            if data.len() < 25 {
+                bail!("IAPREFIX is truncated");
+            }
+            let prefix = ipnet::Ipv6Net::new(addr_from(&data[9..25]), data[8])
+                .context("bad delegated prefix length")?;
+            self.prefixes.push(prefix);
+        }
+        Ok(())
+    }
+
+    fn parse_mape(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        for (code, data) in options(data)? {
+            match code {
+                OPTION_S46_RULE => {
+                    // flags, EA length, IPv4 prefix length, IPv4 prefix, IPv6 prefix length, then
+                    // as many bytes of IPv6 prefix as that length needs
+                    if data.len() < 8 {
+                        bail!("S46 rule is truncated");
+                    }
+                    let ipv6_len = data[7];
+                    // Checked before it's used to slice, as this comes straight off the network
+                    if ipv6_len > 128 {
+                        bail!("S46 rule prefix length over 128");
+                    }
+                    let prefix_bytes = (ipv6_len as usize).div_ceil(8);
+                    let Some(prefix) = data.get(8..8 + prefix_bytes) else {
+                        bail!("S46 rule prefix is truncated");
+                    };
+                    let mut psid_offset = DEFAULT_PSID_OFFSET;
+                    for (code, data) in options(&data[8 + prefix_bytes..])? {
+                        // offset, PSID length and PSID, of which only the offset is news to us
+                        if code == OPTION_S46_PORTPARAMS && !data.is_empty() {
+                            psid_offset = data[0];
+                        }
+                    }
+                    let bmr = v6plus_tun::Bmr {
+                        ipv6_prefix: addr_from(prefix),
+                        ipv6_len,
+                        ipv4_prefix: std::net::Ipv4Addr::new(data[3], data[4], data[5], data[6]),
+                        ipv4_len: data[2],
+                        ea_len: data[1],
+                        psid_offset,
+                    };
+                    bmr.validate()
+                        .map_err(anyhow::Error::msg)
+                        .context("bad S46 rule")?;
+                    // The F flag is the flags' lowest bit (RFC 7598 section 4.1)
+                    if data[0] & 1 != 0 {
+                        self.fmrs.push(bmr);
+                    }
+                    self.rules.push(bmr);
+                }
+                OPTION_S46_BR => {
+                    if data.len() != 16 {
+                        bail!("S46 BR is not an IPv6 address");
+                    }
+                    self.br = Some(addr_from(data));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Ask the DHCPv6 servers on dev for MAP-E options with an Information-Request (RFC 8415 section
+// 18.2.6), the way a CE that's provisioned over DHCPv6 gets them. The Reply has no prefix in it,
+// so only the rules and BR are filled in.
+#[cfg(target_os = "linux")]
+pub fn request_info(dev: &str, timeout: std::time::Duration) -> anyhow::Result<Lease> {
+    use std::net::{Ipv6Addr, SocketAddrV6};
+
+    const INFORMATION_REQUEST: u8 = 11;
+    const REPLY: u8 = 7;
+    const OPTION_CLIENTID: u16 = 1;
+    const OPTION_ORO: u16 = 6;
+    const OPTION_ELAPSED_TIME: u16 = 8;
+
+    let sys = |f: &str| std::fs::read_to_string(format!("/sys/class/net/{dev}/{f}"));
+    let ifindex: u32 = sys("ifindex")
+        .with_context(|| format!("no device {dev}"))?
+        .trim()
+        .parse()?;
+    let mac: Vec<u8> = sys("address")?
+        .trim()
+        .split(':')
+        .filter_map(|b| u8::from_str_radix(b, 16).ok())
+        .collect();
+
+    let socket = std::net::UdpSocket::bind("[::]:546").context(
+        "could not bind the DHCPv6 client port; if a DHCPv6 client is already running, pass its lease with --lease instead",
+    )?;
+    socket.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+    // All_DHCP_Relay_Agents_and_Servers
+    let servers = SocketAddrV6::new(Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 1, 2), 547, 0, ifindex);
+
+    let xid = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .subsec_nanos()
+        .to_be_bytes();
+    let mut msg = vec![INFORMATION_REQUEST, xid[1], xid[2], xid[3]];
+    let mut option = |code: u16, data: &[u8]| {
+        msg.extend(code.to_be_bytes());
+        msg.extend((data.len() as u16).to_be_bytes());
+        msg.extend(data);
+    };
+    // DUID-LL (type 3, hardware type 1) from the WAN's MAC, as a real CE would identify itself
+    option(OPTION_CLIENTID, &[[0, 3, 0, 1].as_slice(), &mac].concat());
+    option(OPTION_ELAPSED_TIME, &[0, 0]);
+    option(OPTION_ORO, &OPTION_S46_CONT_MAPE.to_be_bytes());
+
+    let start = std::time::Instant::now();
+    let mut buf = [0; 1500];
+    while start.elapsed() < timeout {
+        socket
+            .send_to(&msg, servers)
+            .with_context(|| format!("could not send an Information-Request on {dev}"))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e).context("could not receive a DHCPv6 Reply"),
+        };
+        let reply = &buf[..len];
+        if reply.len() >= 4 && reply[0] == REPLY && reply[1..4] == msg[1..4] {
+            return Lease::parse(reply).context("bad DHCPv6 Reply");
+        }
+    }
+    bail!("no DHCPv6 Reply on {dev} within {timeout:?}");
+}
@@ -0,0 +1,81 @@
+// Just enough DNS to tell whether a resolver answers over IPv6. Names resolve over IPv6 even when
+// IPv4 goes through the tunnel, so a resolver that's unreachable (or gone, after the ISP
+// renumbered) makes everything look broken, the tunnel included.
+
+use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+
+// AAAA, since that's what breaks first without the NGN's resolvers
+const QTYPE_AAAA: u16 = 28;
+
+// How long the resolver took to answer a query for name. NXDOMAIN is an answer too; SERVFAIL
+// means the resolver is up but can't resolve anything itself.
+pub fn check(resolver: Ipv6Addr, name: &str, timeout: Duration) -> anyhow::Result<Duration> {
+    let id = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .subsec_nanos() as u16;
+    // Header: ID, flags with recursion desired, one question and no other records
+    let mut query = vec![];
+    query.extend(id.to_be_bytes());
+    query.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("'{name}' is not a valid name");
+        }
+        query.push(label.len() as u8);
+        query.extend(label.as_bytes());
+    }
+    query.push(0);
+    query.extend(QTYPE_AAAA.to_be_bytes());
+    // Class IN
+    query.extend([0, 1]);
+
+    let socket = std::net::UdpSocket::bind("[::]:0")?;
+    socket
+        .connect((resolver, 53))
+        .with_context(|| format!("no route to {resolver}"))?;
+    let start = Instant::now();
+    socket
+        .send(&query)
+        .with_context(|| format!("could not send a query to {resolver}"))?;
+    let mut buf = [0; 512];
+    loop {
+        let Some(left) = timeout.checked_sub(start.elapsed()) else {
+            bail!("{resolver} didn't answer within {timeout:?}");
+        };
+        socket.set_read_timeout(Some(left.max(Duration::from_millis(1))))?;
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                bail!("{resolver} didn't answer within {timeout:?}");
+            }
+            Err(e) => return Err(e).with_context(|| format!("no answer from {resolver}")),
+        };
+        // Something else's reply, or not a reply at all
+        if len < 12 || buf[..2] != id.to_be_bytes() || buf[2] & 0x80 == 0 {
+            continue;
+        }
+        return match buf[3] & 0x0f {
+            0 | 3 => Ok(start.elapsed()),
+            2 => bail!("{resolver} answered SERVFAIL for {name}: it's up, but can't resolve"),
+            5 => bail!("{resolver} refused the query for {name}: it doesn't serve this line"),
+            rcode => bail!("{resolver} answered {name} with error {rcode}"),
+        };
+    }
+}
+
+// The IPv6 nameservers in resolv.conf, i.e. the ones the system uses over IPv6
+pub fn system_resolvers() -> Vec<Ipv6Addr> {
+    std::fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.strip_prefix("nameserver")?.trim().parse().ok())
+        .collect()
+}
@@ -0,0 +1,365 @@
+// Diagnosing a line before (or instead of) setting it up: each check says what it found and, when
+// something is off, what that means and what to do about it.
+
+use anyhow::bail;
+use clap::Parser;
+use cmd_lib::{run_cmd, run_fun};
+
+use crate::nat::{self, FwRule};
+use crate::{dhcp6, dns, iface, MapEData};
+
+#[derive(Parser)]
+pub struct Doctor {
+    #[arg(
+        long = "wan",
+        required = true,
+        help = "WAN interface device, such as 'eth0', 'mac:52:54:00:12:34:56' or 'prefix:240b:10::/31' for whichever holds an address in it"
+    )]
+    wan_dev: String,
+    #[arg(
+        long,
+        visible_alias = "from-lease",
+        help = "DHCPv6 client state to take the delegated prefix (and MAP-E options, if any) from: dhcpcd, odhcp6c or wide-dhcpv6, optionally with :PATH, or a dhcpcd lease file"
+    )]
+    lease: Option<String>,
+    #[arg(
+        long = "tun",
+        default_value = "ip4tun0",
+        help = "Tunnel interface setup-linux created, for the MTU checks"
+    )]
+    tun_dev: String,
+    #[arg(
+        long = "lan",
+        help = "LAN interface whose MTU to compare with the tunnel's, repeatable. Without it, LAN hosts are taken to use 1500"
+    )]
+    lan_devs: Vec<String>,
+    #[arg(
+        long,
+        help = "Fix MTU mismatches: lower the tunnel MTU to fit the WAN's, and add or widen MSS clamping"
+    )]
+    fix: bool,
+    #[arg(
+        long = "resolver",
+        help = "IPv6 resolver to check, repeatable. By default, the lease's DNS servers, or else resolv.conf's IPv6 nameservers"
+    )]
+    resolvers: Vec<std::net::Ipv6Addr>,
+    #[arg(
+        long,
+        default_value = "example.com",
+        help = "Name to look up through the resolvers"
+    )]
+    dns_name: String,
+}
+
+// The existing MSS clamping rule for the tunnel, and how to replace it
+enum Clamp {
+    Iptables {
+        chain: String,
+        num: usize,
+        from: u16,
+    },
+    Nft {
+        handle: u64,
+        from: u16,
+    },
+}
+
+impl Clamp {
+    fn from(&self) -> u16 {
+        match self {
+            Clamp::Iptables { from, .. } | Clamp::Nft { from, .. } => *from,
+        }
+    }
+}
+
+enum Finding {
+    Ok(String),
+    Warn(String),
+    Fail(String),
+}
+
+impl Doctor {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let findings = self.findings();
+        let mut failed = false;
+        for finding in &findings {
+            match finding {
+                Finding::Ok(msg) => println!("ok    {msg}"),
+                Finding::Warn(msg) => println!("warn  {msg}"),
+                Finding::Fail(msg) => {
+                    println!("FAIL  {msg}");
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            bail!("some checks failed");
+        }
+        Ok(())
+    }
+
+    // Stops at the first failure, since later checks build on the earlier ones
+    fn findings(&self) -> Vec<Finding> {
+        let mut findings = vec![];
+        let wan_dev = match iface::resolve_dev(&self.wan_dev) {
+            Ok(dev) => {
+                findings.push(Finding::Ok(format!("WAN device {dev}")));
+                dev
+            }
+            Err(e) => {
+                findings.push(Finding::Fail(format!("{e:#}")));
+                return findings;
+            }
+        };
+        let lease = match self
+            .lease
+            .as_deref()
+            .map(dhcp6::Lease::from_spec)
+            .transpose()
+        {
+            Ok(lease) => lease,
+            Err(e) => {
+                findings.push(Finding::Fail(format!("{e:#}")));
+                return findings;
+            }
+        };
+        let addr = match iface::select_wan_addr(&wan_dev, |a| MapEData::from_addr(a).is_ok()) {
+            Ok(addr) => {
+                findings.push(Finding::Ok(format!("{addr} is in a known prefix")));
+                addr
+            }
+            Err(e) => {
+                findings.push(Finding::Fail(format!("{e:#}")));
+                return findings;
+            }
+        };
+        // select_wan_addr only picks addresses a rule covers
+        let rule = crate::rules::lookup(addr).unwrap();
+        findings.push(self.prefix_len(addr, rule, lease.as_ref()));
+        findings.extend(self.mtu(&wan_dev));
+        findings.extend(self.dns(lease.as_ref()));
+        findings
+    }
+
+    // Names resolve over IPv6, through the NGN's own resolvers, whatever happens to IPv4. With
+    // those unreachable everything fails to connect by name, which looks just like a broken tunnel.
+    fn dns(&self, lease: Option<&dhcp6::Lease>) -> Vec<Finding> {
+        let (resolvers, from) = match lease {
+            _ if !self.resolvers.is_empty() => (self.resolvers.clone(), "--resolver"),
+            Some(lease) if !lease.dns.is_empty() => (lease.dns.clone(), "the lease"),
+            _ => (dns::system_resolvers(), "resolv.conf"),
+        };
+        if resolvers.is_empty() {
+            return vec![Finding::Warn(
+                "no IPv6 resolvers in the lease or resolv.conf, so IPv6 DNS wasn't checked. Pass the NGN's (e.g. from the HGW or the ISP's documentation) with --resolver".to_string(),
+            )];
+        }
+        resolvers
+            .into_iter()
+            .map(|resolver| {
+                match dns::check(resolver, &self.dns_name, std::time::Duration::from_secs(2)) {
+                    Ok(took) => Finding::Ok(format!(
+                        "resolver {resolver} (from {from}) answered for {} in {}ms",
+                        self.dns_name,
+                        took.as_millis()
+                    )),
+                    Err(e) => Finding::Fail(format!(
+                        "{e:#}. Broken IPv6 DNS looks just like a broken tunnel, so check this first: the resolver may be wrong (from {from}), or IPv6 to it blocked"
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    // The tunnel has to fit in the WAN with its 40-byte IPv6 header, and TCP from LAN hosts with
+    // a bigger MTU has to be clamped to it: ICMP "fragmentation needed" rarely makes it back
+    // through the BR, so otherwise connections stall once they send a full-size packet.
+    fn mtu(&self, wan_dev: &str) -> Vec<Finding> {
+        let tun_dev = &self.tun_dev;
+        let mut findings = vec![];
+        let Some(wan_mtu) = read_mtu(wan_dev) else {
+            findings.push(Finding::Fail(format!("could not read {wan_dev}'s MTU")));
+            return findings;
+        };
+        let Some(mut tun_mtu) = read_mtu(tun_dev) else {
+            findings.push(Finding::Ok(format!(
+                "no tunnel {tun_dev} yet, so no MTUs to compare"
+            )));
+            return findings;
+        };
+        let fits = wan_mtu - 40;
+        if tun_mtu <= fits {
+            findings.push(Finding::Ok(format!(
+                "{tun_dev} MTU {tun_mtu} fits in {wan_dev}'s {wan_mtu}"
+            )));
+        } else {
+            let problem = format!("{tun_dev} MTU {tun_mtu} is more than {wan_dev}'s {wan_mtu} leaves after the IPv6 header ({fits}), so full-size packets are fragmented or dropped");
+            findings.push(self.fixed(problem, || {
+                run_cmd!(ip link set dev $tun_dev mtu $fits)?;
+                tun_mtu = fits;
+                Ok(format!("set {tun_dev} MTU to {fits}"))
+            }));
+        }
+
+        let lans: Vec<(String, Option<u32>)> = if self.lan_devs.is_empty() {
+            vec![("LAN hosts".to_string(), Some(1500))]
+        } else {
+            self.lan_devs
+                .iter()
+                .map(|dev| (format!("LAN {dev}"), read_mtu(dev)))
+                .collect()
+        };
+        let mut biggest = 0;
+        for (lan, mtu) in lans {
+            match mtu {
+                Some(mtu) => biggest = biggest.max(mtu),
+                None => findings.push(Finding::Fail(format!("could not read {lan}'s MTU"))),
+            }
+        }
+        if biggest <= tun_mtu {
+            return findings;
+        }
+        // SYNs advertising more than this have to be clamped
+        let mss = (tun_mtu - 40) as u16;
+        let from = nat::CLAMP_FROM.min(mss);
+        let clamp = clamp(tun_dev);
+        let problem = match &clamp {
+            Some(clamp) if clamp.from() <= mss + 1 => {
+                findings.push(Finding::Ok(format!(
+                    "LAN MTU {biggest} over {tun_dev}'s {tun_mtu}, with MSS clamping"
+                )));
+                return findings;
+            }
+            Some(clamp) => format!(
+                "LAN MTU {biggest} over {tun_dev}'s {tun_mtu}, but MSS is only clamped from {}, so SYNs advertising {}-{} aren't",
+                clamp.from(),
+                mss + 1,
+                clamp.from() - 1
+            ),
+            None => format!(
+                "LAN MTU {biggest} over {tun_dev}'s {tun_mtu} without MSS clamping, so TCP connections stall once they send a full-size packet"
+            ),
+        };
+        findings.push(self.fixed(problem, || {
+            let rule = FwRule::ClampMss { from };
+            match clamp {
+                Some(Clamp::Iptables { chain, num, .. }) => {
+                    let (_, _, args) = rule.iptables(tun_dev);
+                    run_cmd!(iptables -t mangle -R $chain $num $[args])?;
+                }
+                Some(Clamp::Nft { handle, .. }) => {
+                    let (_, rule) = rule.nft(tun_dev)?;
+                    let rule: Vec<_> = rule.split_whitespace().collect();
+                    run_cmd!(nft replace rule ip v6plus_tun forward handle $handle $[rule])?;
+                }
+                // Wherever setup-linux put the rest of the rules
+                None if run_fun!(nft list table ip v6plus_tun 2>/dev/null).is_ok() => {
+                    let (_, rule) = rule.nft(tun_dev)?;
+                    let rule: Vec<_> = rule.split_whitespace().collect();
+                    run_cmd!(nft insert rule ip v6plus_tun forward $[rule])?;
+                }
+                None => {
+                    let (_, _, args) = rule.iptables(tun_dev);
+                    let ours = nat::mangle_chain("FORWARD");
+                    let chain = match run_fun!(iptables -t mangle -S $ours 2>/dev/null) {
+                        Ok(_) => ours,
+                        Err(_) => "FORWARD".to_string(),
+                    };
+                    run_cmd!(iptables -t mangle -I $chain 1 $[args])?;
+                }
+            }
+            Ok(format!("clamped MSS from {from}"))
+        }));
+        findings
+    }
+
+    // A problem as a failure, or with --fix, what was done about it
+    fn fixed(&self, problem: String, fix: impl FnOnce() -> anyhow::Result<String>) -> Finding {
+        if !self.fix {
+            return Finding::Fail(format!("{problem}. --fix fixes it"));
+        }
+        match fix() {
+            Ok(done) => Finding::Ok(format!("{problem}: fixed, {done}")),
+            Err(e) => Finding::Fail(format!("{problem}, and fixing it failed: {e:#}")),
+        }
+    }
+
+    // The rule maps one end-user prefix per customer. Getting a longer one means part of it is
+    // someone else's (typically the HGW's), a shorter one that only part of it is mapped.
+    fn prefix_len(
+        &self,
+        addr: std::net::Ipv6Addr,
+        rule: &crate::rules::Rule,
+        lease: Option<&dhcp6::Lease>,
+    ) -> Finding {
+        let expected = rule.bmr.ipv6_len + rule.bmr.ea_len;
+        let delegated = match lease {
+            Some(lease) => lease.prefixes.iter().find(|p| p.contains(&addr)).copied(),
+            None => iface::delegated_prefix(addr).ok().flatten(),
+        };
+        let Some(delegated) = delegated else {
+            return Finding::Warn(format!(
+                "no delegated prefix covers {addr}, only a /64 from router advertisements. The rule maps a /{expected} per customer, so either the HGW holds the /{expected} (a Hikari Denwa contract) and may be terminating MAP-E with the same IPv4 address and ports, or the DHCPv6 client never asked for a delegation. Turn on prefix delegation (dhcpcd: 'ia_pd 1' for the WAN), or turn off the HGW's IPv4 over IPv6 before setting up"
+            ));
+        };
+        let len = delegated.prefix_len();
+        if len == expected {
+            Finding::Ok(format!(
+                "delegated {delegated}, the /{expected} the rule expects"
+            ))
+        } else if len > expected {
+            Finding::Warn(format!(
+                "delegated {delegated}, but the rule maps a /{expected} per customer: the rest of it is most likely the HGW's, which then also uses our IPv4 address and ports. Connect directly to the ONU, or turn off the HGW's IPv4 over IPv6 before setting up"
+            ))
+        } else {
+            Finding::Warn(format!(
+                "delegated {delegated}, more than the /{expected} the rule maps: only {} is mapped, to the calculated IPv4 address. Addresses outside it would be calculated as someone else's, so keep the WAN and tunnel addresses inside it",
+                ipnet::Ipv6Net::new(addr, expected).unwrap().trunc()
+            ))
+        }
+    }
+}
+
+fn read_mtu(dev: &str) -> Option<u32> {
+    std::fs::read_to_string(format!("/sys/class/net/{dev}/mtu"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// The first rule clamping MSS for the tunnel, in setup-linux's chain, iptables' FORWARD or
+// setup-linux --nft's table
+fn clamp(tun_dev: &str) -> Option<Clamp> {
+    let oif = format!("-o {tun_dev} ");
+    for chain in [nat::mangle_chain("FORWARD"), "FORWARD".to_string()] {
+        let Ok(rules) = run_fun!(iptables -t mangle -S $chain 2>/dev/null) else {
+            continue;
+        };
+        // Rules are numbered from 1, after the policy line (or a chain of our own's -N line)
+        for (num, rule) in rules.lines().enumerate() {
+            if !rule.contains(&oif) || !rule.contains("TCPMSS") {
+                continue;
+            }
+            let tokens: Vec<_> = rule.split_whitespace().collect();
+            let from = tokens
+                .iter()
+                .position(|t| *t == "--mss")
+                .and_then(|i| tokens.get(i + 1))
+                .map_or(Some(0), |range| range.split(':').next()?.parse().ok())?;
+            return Some(Clamp::Iptables { chain, num, from });
+        }
+    }
+    let oif = format!("oifname \"{tun_dev}\" ");
+    let rules = run_fun!(nft -a list chain ip v6plus_tun forward 2>/dev/null).ok()?;
+    rules
+        .lines()
+        .filter(|r| r.contains(&oif) && r.contains("maxseg size set"))
+        .find_map(|rule| {
+            let from = rule
+                .split_once("maxseg size ")
+                .map_or(Some(0), |(_, rest)| rest.split('-').next()?.parse().ok())?;
+            let handle = rule.rsplit_once("# handle ")?.1.trim().parse().ok()?;
+            Some(Clamp::Nft { handle, from })
+        })
+}
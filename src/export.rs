@@ -0,0 +1,202 @@
+// Exporters render the parameters, and what setup-linux would do with them, in formats other
+// tools understand.
+
+use std::fmt::Write;
+
+use anyhow::Context;
+use clap::Parser;
+
+use crate::NatArgs;
+use crate::{copy_to_clipboard, AddrArgs, MapEData};
+
+#[derive(Clone, clap::ValueEnum)]
+pub enum Format {
+    /// Graphviz diagram of the packet path
+    Dot,
+    /// Mermaid flowchart of the packet path
+    Mermaid,
+    /// iptables-restore and nft -f files with the NAT rules, for the distro's firewall to load
+    Persist,
+}
+
+#[derive(Parser)]
+pub struct Export {
+    #[arg(value_enum)]
+    format: Format,
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long = "wan",
+        default_value = "WAN",
+        help = "WAN interface name to show"
+    )]
+    wan_dev: String,
+    #[arg(
+        long = "tun",
+        default_value = "ip4tun0",
+        help = "Tunnel interface name to show"
+    )]
+    tun_dev: String,
+    #[arg(long, help = "Also copy the output to the clipboard")]
+    copy: bool,
+    #[command(flatten)]
+    nat: NatArgs,
+    #[arg(
+        long,
+        default_value = ".",
+        help = "Directory to write persist's v6plus-tun.rules and v6plus-tun.nft to"
+    )]
+    dir: std::path::PathBuf,
+}
+
+impl Export {
+    pub fn export(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let out = match self.format {
+            Format::Dot => render_dot(&self.packet_path(&data)?),
+            Format::Mermaid => render_mermaid(&self.packet_path(&data)?),
+            Format::Persist => return self.persist(&data),
+        };
+        print!("{out}");
+        if self.copy {
+            copy_to_clipboard(&out)?;
+        }
+        Ok(())
+    }
+
+    // Only the NAT rules: the tunnel and routes still need setting up some other way, e.g. by
+    // networkd
+    fn persist(&self, data: &MapEData) -> anyhow::Result<()> {
+        let plan = self.nat.plan(&data.port_ranges)?;
+        let rules = self.nat.rules(&plan, data.ipv4_addr);
+        let files = [
+            (
+                "v6plus-tun.rules",
+                crate::nat::iptables_restore(&rules, &self.tun_dev),
+            ),
+            ("v6plus-tun.nft", crate::nat::nft(&rules, &self.tun_dev)?),
+        ];
+        for (name, contents) in files {
+            let path = self.dir.join(name);
+            std::fs::write(&path, contents)
+                .with_context(|| format!("could not write {}", path.display()))?;
+            println!("wrote {}", path.display());
+        }
+        Ok(())
+    }
+
+    // The path an outgoing IPv4 packet takes through what setup-linux installs
+    fn packet_path(&self, data: &MapEData) -> anyhow::Result<Diagram> {
+        let mut nodes = vec![("lan".to_string(), vec!["LAN".to_string()])];
+        let mut edges = vec![];
+
+        let plan = self.nat.plan(&data.port_ranges)?;
+        let hashed = plan.ranges.len() > 1;
+        // The router's own traffic goes the same way, marked in OUTPUT instead. Unmarked, it only
+        // gets SNAT'd with a single range.
+        let local = !hashed || !self.nat.no_local_marks;
+        if local {
+            nodes.push(("router".to_string(), vec!["this router".to_string()]));
+        }
+        let snat_from: &[&str] = if hashed {
+            let marks = plan.marks();
+            let label = format!(
+                "HMARK sport mod {} -> mark {:#x}-{:#x}",
+                plan.buckets(),
+                marks[0].0,
+                marks[marks.len() - 1].0
+            );
+            nodes.push((
+                "hmark".to_string(),
+                vec!["mangle PREROUTING".to_string(), label.clone()],
+            ));
+            edges.push(("lan".to_string(), "hmark".to_string()));
+            if local {
+                nodes.push((
+                    "hmark_out".to_string(),
+                    vec!["mangle OUTPUT".to_string(), label],
+                ));
+                edges.push(("router".to_string(), "hmark_out".to_string()));
+                &["hmark", "hmark_out"]
+            } else {
+                &["hmark"]
+            }
+        } else {
+            &["lan", "router"]
+        };
+        let marks = plan.marks();
+        for (i, ((start, end), _)) in plan.ranges.iter().enumerate() {
+            let id = format!("snat{i}");
+            let mut label = vec![];
+            if hashed {
+                let mut range_marks = marks.iter().filter(|(_, r)| *r == (*start, *end));
+                let first = range_marks.next().unwrap().0;
+                match range_marks.next_back() {
+                    Some((last, _)) => label.push(format!("mark {first:#x}-{last:#x}")),
+                    None => label.push(format!("mark {first:#x}")),
+                }
+            }
+            label.push(format!("SNAT {}:{start}-{end}", data.ipv4_addr));
+            nodes.push((id.clone(), label));
+            for from in snat_from {
+                edges.push((from.to_string(), id.clone()));
+            }
+            edges.push((id, "tun".to_string()));
+        }
+
+        nodes.push((
+            "tun".to_string(),
+            vec![
+                self.tun_dev.clone(),
+                "ip4ip6".to_string(),
+                format!("local {}", data.edge_addr),
+                format!("remote {}", data.br_addr),
+            ],
+        ));
+        nodes.push((
+            "wan".to_string(),
+            vec![self.wan_dev.clone(), data.edge_addr.to_string()],
+        ));
+        nodes.push((
+            "br".to_string(),
+            vec!["BR".to_string(), data.br_addr.to_string()],
+        ));
+        edges.push(("tun".to_string(), "wan".to_string()));
+        edges.push(("wan".to_string(), "br".to_string()));
+        Ok(Diagram { nodes, edges })
+    }
+}
+
+struct Diagram {
+    // (id, label lines)
+    nodes: Vec<(String, Vec<String>)>,
+    // (from id, to id)
+    edges: Vec<(String, String)>,
+}
+
+fn render_dot(Diagram { nodes, edges }: &Diagram) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph v6plus {{").unwrap();
+    writeln!(out, "    rankdir=LR;").unwrap();
+    writeln!(out, "    node [shape=box];").unwrap();
+    for (id, label) in nodes {
+        writeln!(out, "    {id} [label=\"{}\"];", label.join("\\n")).unwrap();
+    }
+    for (from, to) in edges {
+        writeln!(out, "    {from} -> {to};").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn render_mermaid(Diagram { nodes, edges }: &Diagram) -> String {
+    let mut out = String::new();
+    writeln!(out, "flowchart LR").unwrap();
+    for (id, label) in nodes {
+        writeln!(out, "    {id}[\"{}\"]", label.join("<br/>")).unwrap();
+    }
+    for (from, to) in edges {
+        writeln!(out, "    {from} --> {to}").unwrap();
+    }
+    out
+}
@@ -0,0 +1,166 @@
+// Port forwards carried over from the old router. Under MAP-E only our own ports can be forwarded,
+// so some forwards keep working as-is and the rest have to move to a port we own.
+//
+// HGW export formats differ between models, so this takes a plain CSV which is easy to massage
+// an export into:
+//
+//     name,proto,external_port,internal_host,internal_port
+//     minecraft,tcp,25565,192.168.1.10,25565
+//     rtp,udp,10000-10015,192.168.1.20,10000-10015
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use v6plus_tun::ports::PortSet;
+
+use crate::nat::parse_port_range;
+use crate::AddrArgs;
+
+#[derive(Parser)]
+pub struct CheckForwards {
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long,
+        required = true,
+        help = "CSV of forwards (name,proto,external_port,internal_host,internal_port), or '-' for stdin"
+    )]
+    csv: String,
+    #[arg(
+        long,
+        help = "Write the remapped forwards here, in the same CSV format"
+    )]
+    output: Option<String>,
+}
+
+#[derive(Clone)]
+struct Forward {
+    name: String,
+    proto: String,
+    external: (u16, u16),
+    internal_host: String,
+    internal: (u16, u16),
+}
+
+impl Forward {
+    fn parse(line: &str) -> anyhow::Result<Forward> {
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let [name, proto, external, internal_host, internal] = fields[..] else {
+            bail!("expected 5 fields, got {}", fields.len());
+        };
+        let external = parse_port_range(external).map_err(anyhow::Error::msg)?;
+        let internal = parse_port_range(internal).map_err(anyhow::Error::msg)?;
+        if external.1 - external.0 != internal.1 - internal.0 {
+            bail!("external and internal port ranges are different sizes");
+        }
+        Ok(Forward {
+            name: name.to_string(),
+            proto: proto.to_string(),
+            external,
+            internal_host: internal_host.to_string(),
+            internal,
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{}",
+            self.name,
+            self.proto,
+            fmt_ports(self.external),
+            self.internal_host,
+            fmt_ports(self.internal)
+        )
+    }
+}
+
+impl CheckForwards {
+    pub fn check(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let csv = if self.csv == "-" {
+            std::io::read_to_string(std::io::stdin())?
+        } else {
+            std::fs::read_to_string(&self.csv)
+                .with_context(|| format!("could not read {}", self.csv))?
+        };
+        let mut forwards = vec![];
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("name,") {
+                continue;
+            }
+            forwards.push(
+                Forward::parse(line).with_context(|| format!("{}: line {}", self.csv, i + 1))?,
+            );
+        }
+
+        let ours = PortSet::new(data.port_ranges.iter().copied());
+        let externals: Vec<_> = forwards.iter().map(|f| f.external).collect();
+        let mut remapped = vec![];
+        let mut unmappable = 0;
+        for (f, new) in forwards.iter().zip(ours.remap(&externals)) {
+            match new {
+                Some(new) if new == f.external => {
+                    println!(
+                        "ok:    {} ({} {}) is on owned ports",
+                        f.name,
+                        f.proto,
+                        fmt_ports(f.external)
+                    );
+                    remapped.push(f.clone());
+                }
+                Some(new) => {
+                    println!(
+                        "move:  {} ({} {}) isn't ours, use {} instead",
+                        f.name,
+                        f.proto,
+                        fmt_ports(f.external),
+                        fmt_ports(new)
+                    );
+                    remapped.push(Forward {
+                        external: new,
+                        ..f.clone()
+                    });
+                }
+                None => {
+                    println!(
+                        "lost:  {} ({} {}) doesn't fit in any free owned range",
+                        f.name,
+                        f.proto,
+                        fmt_ports(f.external)
+                    );
+                    unmappable += 1;
+                }
+            }
+        }
+
+        if !remapped.is_empty() {
+            // Otherwise outgoing connections can be SNAT'd onto a forwarded port
+            let flags: Vec<String> = remapped
+                .iter()
+                .map(|f| format!("--no-snat-port {}", fmt_ports(f.external)))
+                .collect();
+            println!();
+            println!("Keep these out of SNAT with: {}", flags.join(" "));
+        }
+        if let Some(output) = &self.output {
+            let mut out = "name,proto,external_port,internal_host,internal_port\n".to_string();
+            for f in &remapped {
+                out += &f.to_csv();
+                out += "\n";
+            }
+            std::fs::write(output, out).with_context(|| format!("could not write {output}"))?;
+        }
+        if unmappable > 0 {
+            bail!("{unmappable} forwards can't be kept");
+        }
+        Ok(())
+    }
+}
+
+fn fmt_ports((start, end): (u16, u16)) -> String {
+    if start == end {
+        start.to_string()
+    } else {
+        format!("{start}-{end}")
+    }
+}
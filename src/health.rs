@@ -0,0 +1,46 @@
+// A minimal HTTP health endpoint, so setup-linux can stay in the foreground of a container and let
+// the orchestrator restart it when the tunnel goes away. /healthz is liveness (restart me),
+// /readyz readiness (IPv4 actually works through us).
+
+use std::io::{BufRead, Write};
+
+use anyhow::Context;
+
+// Serve forever. check(ready) returns why the check fails, if it does.
+pub fn serve(
+    listen: std::net::SocketAddr,
+    check: impl Fn(bool) -> Result<(), String>,
+) -> anyhow::Result<()> {
+    let listener = std::net::TcpListener::bind(listen)
+        .with_context(|| format!("could not listen on {listen}"))?;
+    eprintln!("serving /healthz and /readyz on {listen}");
+    for stream in listener.incoming() {
+        // A client hanging up early is its problem, not ours
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        let _ = stream.set_read_timeout(Some(std::time::Duration::from_secs(5)));
+        let mut request = String::new();
+        if std::io::BufReader::new(&stream)
+            .read_line(&mut request)
+            .is_err()
+        {
+            continue;
+        }
+        // e.g. "GET /healthz HTTP/1.1"
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+        let (status, body) = match path {
+            "/healthz" | "/readyz" => match check(path == "/readyz") {
+                Ok(()) => ("200 OK", "ok\n".to_string()),
+                Err(e) => ("503 Service Unavailable", format!("{e}\n")),
+            },
+            _ => ("404 Not Found", "not found\n".to_string()),
+        };
+        let _ = write!(
+            stream,
+            "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+    }
+    Ok(())
+}
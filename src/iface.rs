@@ -0,0 +1,386 @@
+// Interface discovery. Everything here shells out to `ip` and parses its one-line output format,
+// much like the setup code shells out to make changes.
+
+use anyhow::{bail, Context};
+use cmd_lib::run_fun;
+
+#[derive(Debug, Clone)]
+pub struct IfaceAddr {
+    pub addr: std::net::Ipv6Addr,
+    pub prefix_len: u8,
+    pub temporary: bool,
+    pub deprecated: bool,
+    pub tentative: bool,
+    pub dadfailed: bool,
+    // None means "forever"
+    pub valid_lft: Option<u32>,
+}
+
+impl IfaceAddr {
+    // Parses a single line of `ip -6 -o addr show`, such as:
+    // 2: eth0    inet6 2404:7a80:1234:5600::1/64 scope global dynamic mngtmpaddr \
+    //     valid_lft 86182sec preferred_lft 14182sec
+    // (which is all one line in the real output)
+    fn parse(line: &str) -> Option<IfaceAddr> {
+        let mut tokens = line.split_whitespace().skip_while(|t| *t != "inet6");
+        tokens.next()?;
+        let (addr, prefix_len) = tokens.next()?.split_once('/')?;
+        let mut ret = IfaceAddr {
+            addr: addr.parse().ok()?,
+            prefix_len: prefix_len.parse().ok()?,
+            temporary: false,
+            deprecated: false,
+            tentative: false,
+            dadfailed: false,
+            valid_lft: None,
+        };
+        while let Some(token) = tokens.next() {
+            match token {
+                "temporary" => ret.temporary = true,
+                "deprecated" => ret.deprecated = true,
+                "tentative" => ret.tentative = true,
+                "dadfailed" => ret.dadfailed = true,
+                "valid_lft" => {
+                    ret.valid_lft = tokens
+                        .next()
+                        .and_then(|lft| lft.strip_suffix("sec"))
+                        .and_then(|lft| lft.parse().ok());
+                }
+                _ => {}
+            }
+        }
+        Some(ret)
+    }
+
+    pub fn usable(&self) -> bool {
+        !(self.deprecated || self.tentative || self.dadfailed)
+    }
+}
+
+// Find the device a WAN selector refers to: a plain device name, "mac:<address>", or
+// "prefix:<IPv6 prefix>" for the device holding a global address in that prefix. The latter two
+// keep working when NIC names change, e.g. between kernel versions.
+pub fn resolve_dev(selector: &str) -> anyhow::Result<String> {
+    if let Some(mac) = selector.strip_prefix("mac:") {
+        for entry in std::fs::read_dir("/sys/class/net")? {
+            let entry = entry?;
+            let addr = std::fs::read_to_string(entry.path().join("address")).unwrap_or_default();
+            if addr.trim().eq_ignore_ascii_case(mac) {
+                return Ok(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        bail!("no device has MAC address {mac}");
+    }
+    if let Some(prefix) = selector.strip_prefix("prefix:") {
+        let prefix: ipnet::Ipv6Net = prefix
+            .parse()
+            .with_context(|| format!("bad prefix '{prefix}'"))?;
+        let out = run_fun!(ip -6 -o addr show scope global).context("could not list addresses")?;
+        // The device name is the second field, as in "2: eth0    inet6 ..."
+        let dev = out.lines().find_map(|line| {
+            let dev = line.split_whitespace().nth(1)?;
+            let a = IfaceAddr::parse(line)?;
+            prefix.contains(&a.addr).then(|| dev.to_string())
+        });
+        return dev.with_context(|| format!("no device has an address in {prefix}"));
+    }
+    if !std::path::Path::new("/sys/class/net")
+        .join(selector)
+        .exists()
+    {
+        bail!("WAN device {selector} does not exist");
+    }
+    Ok(selector.to_string())
+}
+
+// An existing ip6tnl device's parameters, as in the one-line output of `ip -6 tunnel show ip4tun0`:
+// ip4tun0: ip/ipv6 remote 2404:9200:225:100::64 local 240b:10:1234:5600:6a:4812:3400:5600 dev eth0
+//     encaplimit none hoplimit 64 tclass 0x00 flowlabel 0x00000 (flowinfo 0x00000000)
+pub struct Ip6Tunnel {
+    // "ip/ipv6" for ip4ip6
+    pub mode: String,
+    pub remote: Option<std::net::Ipv6Addr>,
+    pub local: Option<std::net::Ipv6Addr>,
+    pub link: Option<String>,
+}
+
+impl std::fmt::Display for Ip6Tunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_any = |a: Option<std::net::Ipv6Addr>| a.map_or("any".to_string(), |a| a.to_string());
+        write!(
+            f,
+            "{} remote {} local {} dev {}",
+            self.mode,
+            or_any(self.remote),
+            or_any(self.local),
+            self.link.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+// None if there's no such device. A device that isn't an ip6 tunnel at all comes back with an
+// unknown mode, so it never matches what we'd create.
+pub fn ip6_tunnel(dev: &str) -> anyhow::Result<Option<Ip6Tunnel>> {
+    if !std::path::Path::new("/sys/class/net").join(dev).exists() {
+        return Ok(None);
+    }
+    let out = run_fun!(ip -6 tunnel show $dev).unwrap_or_default();
+    let mut tokens = out.split_whitespace().skip(1);
+    let mut ret = Ip6Tunnel {
+        mode: tokens.next().unwrap_or("unknown").to_string(),
+        remote: None,
+        local: None,
+        link: None,
+    };
+    while let Some(token) = tokens.next() {
+        match token {
+            "remote" => ret.remote = tokens.next().and_then(|a| a.parse().ok()),
+            "local" => ret.local = tokens.next().and_then(|a| a.parse().ok()),
+            "dev" => ret.link = tokens.next().map(str::to_string),
+            _ => {}
+        }
+    }
+    Ok(Some(ret))
+}
+
+pub fn global_addrs(dev: &str) -> anyhow::Result<Vec<IfaceAddr>> {
+    let out = run_fun!(ip -6 -o addr show dev $dev scope global)
+        .with_context(|| format!("could not list addresses on {dev}"))?;
+    Ok(out.lines().filter_map(IfaceAddr::parse).collect())
+}
+
+// Every device's, for telling whether an address is this machine's or from its prefix
+pub fn all_global_addrs() -> anyhow::Result<Vec<IfaceAddr>> {
+    let out = run_fun!(ip -6 -o addr show scope global).context("could not list addresses")?;
+    Ok(out.lines().filter_map(IfaceAddr::parse).collect())
+}
+
+// The IPv6 default gateway on a device, as in `default via fe80::1 dev eth0 proto ra ...`. On
+// NGN this is a link-local address, which is only meaningful together with the device.
+pub fn default_gateway(dev: &str) -> anyhow::Result<Option<std::net::Ipv6Addr>> {
+    let out = run_fun!(ip -6 route show default dev $dev)
+        .with_context(|| format!("could not list routes on {dev}"))?;
+    Ok(out.lines().find_map(|line| {
+        let mut tokens = line.split_whitespace().skip_while(|t| *t != "via");
+        tokens.next()?;
+        tokens.next()?.parse().ok()
+    }))
+}
+
+// The delegated prefix covering addr, going by the unreachable route DHCPv6 clients such as dhcpcd
+// install for the unused part of a delegation, as in
+// `unreachable 240b:10:1234:5600::/56 dev lo proto dhcp metric 1024 pref medium`
+pub fn delegated_prefix(addr: std::net::Ipv6Addr) -> anyhow::Result<Option<ipnet::Ipv6Net>> {
+    let out =
+        run_fun!(ip -6 route show type unreachable).context("could not list unreachable routes")?;
+    Ok(out.lines().find_map(|line| {
+        let prefix: ipnet::Ipv6Net = line.split_whitespace().nth(1)?.parse().ok()?;
+        (prefix.prefix_len() < 64 && prefix.contains(&addr)).then_some(prefix)
+    }))
+}
+
+// The device the IPv6 default route goes out of, which is the WAN on any ordinary router
+pub fn default_route_dev() -> anyhow::Result<String> {
+    let out = run_fun!(ip -6 route show default).context("could not list routes")?;
+    out.lines()
+        .find_map(|line| {
+            let mut tokens = line.split_whitespace().skip_while(|t| *t != "dev");
+            tokens.next()?;
+            tokens.next().map(str::to_string)
+        })
+        .context("no IPv6 default route to find the WAN by")
+}
+
+// The incoming interfaces policy-routed to `table`, as in `32765: from all iif guest0 lookup 100`
+pub fn iif_rules(table: u32) -> anyhow::Result<Vec<String>> {
+    let out = run_fun!(ip -4 rule show).context("could not list routing rules")?;
+    let table = table.to_string();
+    Ok(out
+        .lines()
+        .filter_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let after = |key: &str| {
+                let i = tokens.iter().position(|t| *t == key)?;
+                tokens.get(i + 1).copied()
+            };
+            (after("lookup") == Some(table.as_str()))
+                .then(|| after("iif").map(str::to_string))
+                .flatten()
+        })
+        .collect())
+}
+
+// An IPv4 default route, as in `default via 192.168.1.1 dev eth0 proto dhcp src 192.168.1.2 metric
+// 100`
+pub struct DefaultRoute {
+    pub via: Option<std::net::Ipv4Addr>,
+    pub dev: Option<String>,
+    pub proto: Option<String>,
+    pub metric: u32,
+}
+
+impl std::fmt::Display for DefaultRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("default")?;
+        if let Some(via) = self.via {
+            write!(f, " via {via}")?;
+        }
+        if let Some(dev) = &self.dev {
+            write!(f, " dev {dev}")?;
+        }
+        if let Some(proto) = &self.proto {
+            write!(f, " proto {proto}")?;
+        }
+        write!(f, " metric {}", self.metric)
+    }
+}
+
+pub fn ipv4_default_routes() -> anyhow::Result<Vec<DefaultRoute>> {
+    let out = run_fun!(ip -4 route show default).context("could not list routes")?;
+    Ok(out
+        .lines()
+        .map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let after = |key: &str| {
+                let i = tokens.iter().position(|t| *t == key)?;
+                tokens.get(i + 1).copied()
+            };
+            DefaultRoute {
+                via: after("via").and_then(|v| v.parse().ok()),
+                dev: after("dev").map(str::to_string),
+                proto: after("proto").map(str::to_string),
+                metric: after("metric").and_then(|m| m.parse().ok()).unwrap_or(0),
+            }
+        })
+        .collect())
+}
+
+// Whatever is running that would put a default route back after we remove it, going by process
+// names, with how to tell it not to
+pub fn route_manager() -> Option<(&'static str, &'static str)> {
+    const MANAGERS: &[(&str, &str, &str)] = &[
+        (
+            "NetworkManager",
+            "NetworkManager",
+            "set ipv4.never-default yes on its connection",
+        ),
+        (
+            "systemd-network",
+            "systemd-networkd",
+            "set UseGateway=no in its .network file's [DHCPv4] section",
+        ),
+        (
+            "dhcpcd",
+            "dhcpcd",
+            "add nogateway to its interface in dhcpcd.conf",
+        ),
+        (
+            "dhclient",
+            "dhclient",
+            "remove routers from the request line in dhclient.conf",
+        ),
+    ];
+    let running: Vec<String> = std::fs::read_dir("/proc")
+        .ok()?
+        .filter_map(|e| std::fs::read_to_string(e.ok()?.path().join("comm")).ok())
+        .map(|comm| comm.trim().to_string())
+        .collect();
+    MANAGERS
+        .iter()
+        .find(|(comm, _, _)| running.iter().any(|r| r == comm))
+        .map(|&(_, name, fix)| (name, fix))
+}
+
+// Pick the address to calculate from when the WAN has several global addresses, e.g. the old and
+// new prefix during renumbering. Addresses that match a known rule win, then the one with the
+// longest remaining lifetime, since that's the prefix that is sticking around. ULAs (which the
+// kernel counts as global) and temporary addresses are never picked.
+pub fn select_wan_addr(
+    dev: &str,
+    known: impl Fn(std::net::Ipv6Addr) -> bool,
+) -> anyhow::Result<std::net::Ipv6Addr> {
+    let addrs = global_addrs(dev)?;
+    let mut candidates: Vec<_> = addrs
+        .iter()
+        .filter(|a| a.usable() && !a.temporary && a.addr.segments()[0] & 0xfe00 != 0xfc00)
+        .collect();
+    if candidates.is_empty() {
+        bail!("no usable global IPv6 address on {dev}");
+    }
+    candidates.sort_by_key(|a| {
+        (
+            !known(a.addr),
+            std::cmp::Reverse(a.valid_lft.unwrap_or(u32::MAX)),
+        )
+    });
+    let chosen = candidates[0];
+    if candidates.len() > 1 {
+        eprintln!("{dev} has {} global addresses:", candidates.len());
+        for a in &candidates {
+            eprintln!(
+                "  {}/{} (known prefix: {}, valid_lft: {})",
+                a.addr,
+                a.prefix_len,
+                if known(a.addr) { "yes" } else { "no" },
+                a.valid_lft
+                    .map(|l| format!("{l}s"))
+                    .unwrap_or_else(|| "forever".to_string()),
+            );
+        }
+        eprintln!("using {}", chosen.addr);
+    }
+    if !known(chosen.addr) {
+        bail!("no global address on {dev} matches a known prefix");
+    }
+    Ok(chosen.addr)
+}
+
+// Block until the WAN has a usable global address in a known prefix, for boot ordering. The WAN
+// is given as a selector (see resolve_dev), since it may not resolve yet either.
+pub fn wait_for_prefix(
+    wan: &str,
+    timeout: std::time::Duration,
+    known: impl Fn(std::net::Ipv6Addr) -> bool,
+) -> anyhow::Result<IfaceAddr> {
+    let start = std::time::Instant::now();
+    loop {
+        // The device itself may not exist yet this early in boot, so errors just mean "not yet"
+        let found = resolve_dev(wan)
+            .and_then(|dev| global_addrs(&dev))
+            .unwrap_or_default()
+            .into_iter()
+            .find(|a| a.usable() && known(a.addr));
+        if let Some(found) = found {
+            return Ok(found);
+        }
+        if start.elapsed() > timeout {
+            bail!("no global address in a known prefix appeared on {wan} within {timeout:?}");
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+
+// Wait for duplicate address detection on a freshly added address to finish. Until it does the
+// address is "tentative" and can't be used as a source, and if it fails (e.g. the HGW is still
+// using the CE address itself) the tunnel would be silently dead.
+pub fn wait_for_dad(
+    dev: &str,
+    addr: std::net::Ipv6Addr,
+    timeout: std::time::Duration,
+) -> anyhow::Result<()> {
+    let start = std::time::Instant::now();
+    loop {
+        let found = global_addrs(dev)?.into_iter().find(|a| a.addr == addr);
+        match found {
+            None => bail!("{addr} disappeared from {dev}"),
+            Some(a) if a.dadfailed => bail!(
+                "duplicate address detection failed for {addr} on {dev}: something else on the link (often the HGW) is using it. Stop it from terminating MAP-E, or pass --nodad if you're sure"
+            ),
+            Some(a) if !a.tentative => return Ok(()),
+            Some(_) if start.elapsed() > timeout => {
+                bail!("{addr} on {dev} is still tentative after {timeout:?}")
+            }
+            Some(_) => std::thread::sleep(std::time::Duration::from_millis(200)),
+        }
+    }
+}
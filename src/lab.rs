@@ -0,0 +1,100 @@
+// A fake BR in a network namespace, so the whole setup-linux path can be exercised without a
+// real line. The "WAN" is one end of a veth pair, and the namespace on the other end decapsulates
+// ip4ip6 from our CE address and answers on a test address, dropping anything whose source port
+// falls outside our PSID's ranges like a real BR would.
+
+use clap::{Parser, Subcommand};
+use cmd_lib::run_cmd;
+
+use crate::AddrArgs;
+
+// An address from TEST-NET-2 to ping through the tunnel
+const LAB_TARGET: &str = "198.51.100.1";
+
+#[derive(Parser)]
+pub struct Lab {
+    #[command(subcommand)]
+    sub: LabCommand,
+    #[arg(
+        long,
+        default_value = "v6plus-lab",
+        help = "Network namespace holding the fake BR"
+    )]
+    netns: String,
+    #[arg(
+        long = "wan",
+        default_value = "v6lab0",
+        help = "Host end of the veth pair, to pass as setup-linux's --wan"
+    )]
+    wan_dev: String,
+}
+
+#[derive(Subcommand)]
+enum LabCommand {
+    /// Create the fake BR for the given address
+    Up(AddrArgs),
+    /// Remove the fake BR
+    Down,
+}
+
+impl Lab {
+    pub fn run(&self) -> anyhow::Result<()> {
+        match &self.sub {
+            LabCommand::Up(input) => self.up(input),
+            LabCommand::Down => self.down(),
+        }
+    }
+
+    fn up(&self, input: &AddrArgs) -> anyhow::Result<()> {
+        let data = input.calculate()?;
+        let (ns, wan_dev, br_addr, edge_addr, ipv4_addr) = (
+            &self.netns,
+            &self.wan_dev,
+            data.br_addr,
+            data.edge_addr,
+            data.ipv4_addr,
+        );
+        let br_dev = "v6lab1";
+        let target = LAB_TARGET;
+
+        run_cmd!(ip netns add $ns)?;
+        run_cmd!(ip link add $wan_dev type veth peer name $br_dev)?;
+        run_cmd!(ip link set $br_dev netns $ns)?;
+        run_cmd!(ip link set $wan_dev up)?;
+        run_cmd!(ip -n $ns link set lo up)?;
+        run_cmd!(ip -n $ns link set $br_dev up)?;
+
+        // The BR's side; the CE address gets added on our side by setup-linux
+        run_cmd!(ip -n $ns -6 addr add $br_addr/128 dev $br_dev nodad)?;
+        run_cmd!(ip -n $ns -6 route add $edge_addr/128 dev $br_dev)?;
+        run_cmd!(ip -6 route add $br_addr/128 dev $wan_dev)?;
+
+        run_cmd!(ip -n $ns -6 tunnel add brtun0 mode ip4ip6 remote $edge_addr local $br_addr dev $br_dev encaplimit none)?;
+        run_cmd!(ip -n $ns link set brtun0 up)?;
+        run_cmd!(ip -n $ns addr add $target/32 dev lo)?;
+        run_cmd!(ip -n $ns route add $ipv4_addr/32 dev brtun0)?;
+
+        // A real BR drops packets from ports that aren't ours, so make sure we'd notice
+        run_cmd!(ip netns exec $ns iptables -N psid)?;
+        for (start, end) in &data.port_ranges {
+            for proto in ["tcp", "udp"] {
+                run_cmd!(ip netns exec $ns iptables -A psid -p $proto --sport $start:$end -j RETURN)?;
+            }
+        }
+        run_cmd!(ip netns exec $ns iptables -A psid -p icmp -j RETURN)?;
+        run_cmd!(ip netns exec $ns iptables -A psid -j DROP)?;
+        run_cmd!(ip netns exec $ns iptables -A INPUT -i brtun0 -j psid)?;
+
+        println!("Fake BR is up. Now run:");
+        println!("  v6plus-tun setup-linux --wan {wan_dev} {}", data.addr);
+        println!("  ping {target}");
+        Ok(())
+    }
+
+    fn down(&self) -> anyhow::Result<()> {
+        let ns = &self.netns;
+        // Deleting the namespace takes its end of the veth, and so the whole pair, with it
+        run_cmd!(ip netns del $ns)?;
+        Ok(())
+    }
+}
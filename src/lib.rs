@@ -0,0 +1,193 @@
+//! The MAP-E math, free of any CLI parsing or system changes, so that other frontends can reuse it
+//! and auditors can check it on its own.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+#[cfg(feature = "test-fixtures")]
+pub mod fixtures;
+pub mod mape;
+pub mod nat;
+pub mod ports;
+pub mod rules;
+pub mod trie;
+
+/// The PSID v6plus embeds in a user's address: the 7th octet, i.e. the 8 bits right after the
+/// /48.
+pub fn psid_from_addr(addr: Ipv6Addr) -> u8 {
+    addr.octets()[6]
+}
+
+/// The CE address for an address in a rule's prefix, the mapped IPv4 address and PSID.
+///
+/// Only the first 32 bits of `addr` are used. The rest is rebuilt from the IPv4 address and PSID:
+/// the end-user prefix bits the rule takes from the IPv4 address and PSID, followed by an
+/// interface ID in the (pre-RFC 7597) layout JPNE uses, with the IPv4 address shifted into bits
+/// 8-40 and the PSID in bits 48-56.
+pub fn ce_from_parts(addr: Ipv6Addr, ipv4: Ipv4Addr, psid: u8) -> Ipv6Addr {
+    let v6_segs = addr.segments();
+    let ipv4_octets = ipv4.octets();
+    Ipv6Addr::new(
+        v6_segs[0],
+        v6_segs[1],
+        ((ipv4_octets[2] as u16) << 8) + ipv4_octets[3] as u16,
+        (psid as u16) << 8,
+        ipv4_octets[0] as u16,
+        ((ipv4_octets[1] as u16) << 8) + ipv4_octets[2] as u16,
+        (ipv4_octets[3] as u16) << 8,
+        (psid as u16) << 8,
+    )
+}
+
+/// The port ranges owned by `psid`, for a PSID offset of `a` bits and PSID length of `k` bits
+/// (RFC 7597 section 5.1). v6plus uses `a = 4` and `k = 8`, giving 15 ranges of 16 ports.
+///
+/// When `a` is non-zero, the ranges where the offset bits are all zero (ports 0 to 2^(16-a) - 1)
+/// are excluded, as the RFC requires.
+pub fn port_ranges(psid: u16, a: u8, k: u8) -> Vec<(u16, u16)> {
+    assert!(
+        a as u32 + k as u32 <= 16,
+        "PSID offset plus length must fit in 16 bits"
+    );
+    let m = 16 - a as u32 - k as u32;
+    let psid = psid as u32 & ((1 << k) - 1);
+    let first = if a == 0 { 0 } else { 1 };
+    (first..(1u32 << a))
+        .map(|i| {
+            let start = (i << (16 - a as u32)) | (psid << m);
+            (start as u16, (start + (1 << m) - 1) as u16)
+        })
+        .collect()
+}
+
+/// The PSID owning `port`, the reverse of [`port_ranges`]. `None` for the excluded low ports.
+pub fn psid_for_port(port: u16, a: u8, k: u8) -> Option<u16> {
+    assert!(
+        a as u32 + k as u32 <= 16,
+        "PSID offset plus length must fit in 16 bits"
+    );
+    if a != 0 && (port as u32) < 1 << (16 - a as u32) {
+        return None;
+    }
+    let m = 16 - a as u32 - k as u32;
+    Some(((port as u32 >> m) & ((1 << k) - 1)) as u16)
+}
+
+/// A Basic Mapping Rule (RFC 7597 section 5), for running the standard algorithm against any
+/// MAP-E deployment rather than v6plus' fixed layout.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Bmr {
+    pub ipv6_prefix: Ipv6Addr,
+    pub ipv6_len: u8,
+    pub ipv4_prefix: Ipv4Addr,
+    pub ipv4_len: u8,
+    pub ea_len: u8,
+    pub psid_offset: u8,
+}
+
+/// What a [`Bmr`] maps an end-user address to
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Mapping {
+    pub ipv4: Ipv4Addr,
+    pub psid: u16,
+    pub psid_len: u8,
+    /// With the RFC 7597 interface ID: 16 zero bits, the IPv4 address, then the PSID
+    pub ce: Ipv6Addr,
+    pub port_ranges: Vec<(u16, u16)>,
+}
+
+impl Bmr {
+    /// Checks the lengths fit together. Rules that map several IPv4 addresses (i.e. a prefix) to
+    /// one end user aren't supported.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ipv6_len > 64 || self.ipv4_len > 32 {
+            return Err("prefix length too long".to_string());
+        }
+        if self.ipv6_len as u32 + self.ea_len as u32 > 64 {
+            return Err(format!(
+                "a /{} rule prefix leaves room for at most {} EA bits",
+                self.ipv6_len,
+                64 - self.ipv6_len
+            ));
+        }
+        let suffix_len = 32 - self.ipv4_len;
+        if self.ea_len < suffix_len {
+            return Err(format!(
+                "{} EA bits can't hold the {suffix_len}-bit IPv4 suffix of a /{} prefix",
+                self.ea_len, self.ipv4_len
+            ));
+        }
+        if self.psid_offset as u32 + self.psid_len() as u32 > 16 {
+            return Err(format!(
+                "a {}-bit PSID at offset {} doesn't fit in a port",
+                self.psid_len(),
+                self.psid_offset
+            ));
+        }
+        Ok(())
+    }
+
+    /// The EA bits that aren't IPv4 suffix are the PSID
+    pub fn psid_len(&self) -> u8 {
+        self.ea_len - (32 - self.ipv4_len)
+    }
+
+    pub fn contains(&self, addr: Ipv6Addr) -> bool {
+        let mask = u128::MAX
+            .checked_shl(128 - self.ipv6_len as u32)
+            .unwrap_or(0);
+        u128::from(addr) & mask == u128::from(self.ipv6_prefix) & mask
+    }
+
+    /// Applies the rule to an address in its prefix (see RFC 7597 section 5.2). The rule must
+    /// [validate](Bmr::validate).
+    pub fn apply(&self, addr: Ipv6Addr) -> Option<Mapping> {
+        if !self.contains(addr) {
+            return None;
+        }
+        // With no prefix and no EA bits, the shifts would be by 128
+        let mask = |bits: u32| 1u128.checked_shl(bits).map_or(u128::MAX, |b| b - 1);
+        let ea_end = self.ipv6_len as u32 + self.ea_len as u32;
+        let ea = u128::from(addr).checked_shr(128 - ea_end).unwrap_or(0) & mask(self.ea_len as u32);
+        let psid_len = self.psid_len() as u32;
+        let psid = (ea & mask(psid_len)) as u16;
+        let suffix = (ea >> psid_len) as u32;
+        let v4_mask = u32::MAX.checked_shl(32 - self.ipv4_len as u32).unwrap_or(0);
+        let ipv4 = Ipv4Addr::from((u32::from(self.ipv4_prefix) & v4_mask) | suffix);
+
+        let end_user_prefix = u128::from(addr) & !mask(128 - ea_end);
+        let iid = ((u32::from(ipv4) as u128) << 16) | psid as u128;
+        Some(Mapping {
+            ipv4,
+            psid,
+            psid_len: psid_len as u8,
+            ce: Ipv6Addr::from(end_user_prefix | iid),
+            port_ranges: port_ranges(psid, self.psid_offset, psid_len as u8),
+        })
+    }
+
+    /// The end-user prefix (`ipv6_len + ea_len` bits long, rest zero) the rule maps to `ipv4` and
+    /// `psid`, the reverse of [`apply`](Bmr::apply). `None` if `ipv4` isn't in the rule or `psid`
+    /// is too long. The rule must [validate](Bmr::validate).
+    pub fn end_user_prefix(&self, ipv4: Ipv4Addr, psid: u16) -> Option<Ipv6Addr> {
+        let suffix_len = 32 - self.ipv4_len as u32;
+        let v4_mask = u32::MAX.checked_shl(suffix_len).unwrap_or(0);
+        if u32::from(ipv4) & v4_mask != u32::from(self.ipv4_prefix) & v4_mask {
+            return None;
+        }
+        let psid_len = self.psid_len() as u32;
+        if psid as u32 >= 1 << psid_len {
+            return None;
+        }
+        let suffix = (u32::from(ipv4) & !v4_mask) as u128;
+        let ea = (suffix << psid_len) | psid as u128;
+        let ea_end = self.ipv6_len as u32 + self.ea_len as u32;
+        let prefix_mask = u128::MAX
+            .checked_shl(128 - self.ipv6_len as u32)
+            .unwrap_or(0);
+        // With no EA bits, the shift would be by 128
+        let ea = ea.checked_shl(128 - ea_end).unwrap_or(0);
+        Some(Ipv6Addr::from(
+            (u128::from(self.ipv6_prefix) & prefix_mask) | ea,
+        ))
+    }
+}
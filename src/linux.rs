@@ -0,0 +1,903 @@
+// Everything that actually changes the system. This is Linux-only: it shells out to ip(8) and
+// iptables(8), so it isn't built elsewhere, leaving calculate and the exporters usable from a
+// laptop on any OS.
+
+use std::io::{IsTerminal, Write};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use cmd_lib::{run_cmd, run_fun};
+
+use crate::nat;
+use crate::rules::{CeLayout, Confidence, Rule};
+use crate::NatArgs;
+use crate::{dhcp6, dns, health, iface, ndisc, stats, MapEData};
+
+#[derive(Parser)]
+pub struct SetupLinux {
+    #[arg(help = "IPv6 address to calculate from; defaults to picking one from the WAN interface")]
+    addr: Option<std::net::Ipv6Addr>,
+    #[arg(
+        long,
+        visible_alias = "from-lease",
+        conflicts_with = "addr",
+        help = "DHCPv6 client state to take the delegated prefix (and MAP-E options, if any) from: dhcpcd, odhcp6c or wide-dhcpv6, optionally with :PATH, or a dhcpcd lease file"
+    )]
+    lease: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "lease"],
+        help = "Pick the address from this interface instead of the WAN, e.g. a LAN holding the delegated prefix"
+    )]
+    from_interface: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "lease",
+        help = "Ask the WAN's DHCPv6 server for MAP-E options (RFC 7598) and use them instead of the built-in rules, if it sends any"
+    )]
+    dhcpv6: bool,
+    #[arg(
+        long = "wan",
+        required_unless_present = "auto",
+        help = "WAN interface device, such as 'eth0', 'mac:52:54:00:12:34:56' or 'prefix:240b:10::/31' for whichever holds an address in it"
+    )]
+    wan_dev: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "lease", "from_interface", "dhcpv6"],
+        help = "Find the WAN (unless --wan is given) by the IPv6 default route, and the prefix by RA, DHCPv6 lease or the WAN's addresses, in that order"
+    )]
+    auto: bool,
+    #[arg(
+        long = "tun",
+        default_value = "ip4tun0",
+        help = "Tunnel interface to create, such as 'iptun0'"
+    )]
+    tun_dev: String,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = TunMode::Auto,
+        help = "How to create the tunnel"
+    )]
+    tun_mode: TunMode,
+    #[arg(
+        long,
+        help = "Tunnel source address to use instead of the CE address; it must already be configured, nothing is added to the WAN"
+    )]
+    local_addr: Option<std::net::Ipv6Addr>,
+    #[arg(
+        long,
+        help = "IPv6 gateway on the WAN, instead of the one learnt by RA, for static setups; it becomes the IPv6 default if there isn't one"
+    )]
+    wan6_gateway: Option<std::net::Ipv6Addr>,
+    #[arg(long, help = "Skip duplicate address detection for the CE address")]
+    nodad: bool,
+    #[arg(
+        long,
+        help = "Announce the CE address with unsolicited neighbor advertisements after adding it, for neighbors that have it cached against another MAC"
+    )]
+    announce: bool,
+    #[command(flatten)]
+    retry: RetryPolicy,
+    #[arg(
+        long,
+        short,
+        help = "Don't ask for confirmation before changing the system"
+    )]
+    yes: bool,
+    #[arg(
+        long,
+        help = "Delete and recreate the tunnel device if it already exists with different parameters, e.g. left over from a script"
+    )]
+    replace_tunnel: bool,
+    #[command(flatten)]
+    nat: NatArgs,
+    #[arg(
+        long,
+        conflicts_with = "pins",
+        help = "Install the NAT rules with nft, in a table of their own replaced in one transaction, instead of flushing iptables' nat table and adding them one by one"
+    )]
+    nft: bool,
+    #[arg(
+        long = "guest",
+        help = "LAN interface whose IPv4 must not use the tunnel (IPv6 is untouched); repeatable"
+    )]
+    guest_devs: Vec<String>,
+    #[arg(
+        long,
+        help = "IPv4 gateway for guest traffic, such as a second uplink; without one guest IPv4 is unreachable"
+    )]
+    guest_gateway: Option<std::net::Ipv4Addr>,
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "Routing table to use for guest traffic. Interfaces routed to it that aren't given with --guest are taken out of it"
+    )]
+    guest_table: u32,
+    #[arg(
+        long,
+        help = "After setting up, stay running and serve /healthz and /readyz on this address, e.g. 0.0.0.0:9090 for a container's probes"
+    )]
+    health_listen: Option<std::net::SocketAddr>,
+    #[arg(
+        long,
+        help = "After setting up, check the IPv6 resolvers (--resolver, or the DHCPv6 lease's) answer, and put those that do first in the system resolver's list"
+    )]
+    set_resolver: bool,
+    #[arg(
+        long = "resolver",
+        requires = "set_resolver",
+        help = "IPv6 resolver for --set-resolver, instead of the lease's; repeatable"
+    )]
+    resolvers: Vec<std::net::Ipv6Addr>,
+    #[arg(
+        long = "i-know-what-im-doing",
+        help = "Set up even if the calculation looks doubtful (an inferred rule, an overridden tunnel address, an inconsistent CE)"
+    )]
+    force: bool,
+    #[arg(
+        long,
+        help = "Take over the IPv4 default route even though IPv4 already goes out with the calculated address, i.e. the HGW is terminating MAP-E itself"
+    )]
+    ignore_hgw: bool,
+    #[arg(
+        long,
+        default_value = stats::DEFAULT_STATE_DIR,
+        help = "Directory to count setups and prefix changes in, for `stats`; nothing leaves the machine"
+    )]
+    state_dir: std::path::PathBuf,
+}
+
+// The same ip6tnl device either way, created through different kernel interfaces
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum TunMode {
+    /// ip4ip6 if the kernel's fallback tunnel device is usable, otherwise ipip6
+    Auto,
+    /// `ip -6 tunnel add ... mode ip4ip6`, as the bash script does. An ioctl on the fallback
+    /// ip6tnl0 device.
+    Ip4ip6,
+    /// `ip link add ... type ip6tnl mode ipip6`, over netlink. This works in network namespaces
+    /// without ip6tnl0 (net.core.fb_tunnels_only_for_init_net) and with minimal `ip`s without
+    /// `ip -6 tunnel`.
+    Ipip6,
+}
+
+impl TunMode {
+    fn resolve(self) -> TunMode {
+        if self != TunMode::Auto {
+            return self;
+        }
+        // With the module not loaded yet, the ioctl loads it and gets ip6tnl0 created. A loaded
+        // module with no ip6tnl0 means this namespace doesn't get one.
+        let module = std::path::Path::new("/sys/module/ip6_tunnel").exists();
+        let fallback = std::path::Path::new("/sys/class/net/ip6tnl0").exists();
+        if module && !fallback {
+            TunMode::Ipip6
+        } else {
+            TunMode::Ip4ip6
+        }
+    }
+}
+
+// What to do about the tunnel device, decided before anything is changed
+enum Tunnel {
+    Create,
+    Adopt,
+    Replace(iface::Ip6Tunnel),
+}
+
+// Most failures at boot are races (the WAN has no address yet, the device is busy, the xtables
+// lock is held), which go away if we just wait a bit. Anything else (a rule that's already there,
+// a bad argument, a missing binary) fails the same way every time, so it isn't retried.
+#[derive(clap::Args)]
+struct RetryPolicy {
+    #[arg(
+        long,
+        default_value_t = 3,
+        help = "Times to retry a step failing with a transient error before giving up"
+    )]
+    retries: u32,
+    #[arg(
+        long,
+        default_value_t = 1.0,
+        help = "Seconds to wait before the first retry, doubling each time"
+    )]
+    retry_delay: f64,
+}
+
+// What commands printed on stderr, most recent last. cmd_lib logs it rather than passing it on,
+// and it's the only place that says why a command failed.
+static STDERR: std::sync::Mutex<std::collections::VecDeque<String>> =
+    std::sync::Mutex::new(std::collections::VecDeque::new());
+
+struct StderrLog;
+
+impl log::Log for StderrLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        let mut lines = STDERR.lock().unwrap();
+        if lines.len() == 20 {
+            lines.pop_front();
+        }
+        lines.push_back(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+// Errors that are worth waiting out, as ip, iptables and the kernel word them
+const TRANSIENT: &[&str] = &[
+    "Device or resource busy",
+    "Resource temporarily unavailable",
+    "xtables lock",
+    "Cannot find device",
+    "No such device",
+    "Network is unreachable",
+    "Cannot assign requested address",
+];
+
+impl RetryPolicy {
+    fn run<T, E: Into<anyhow::Error>>(
+        &self,
+        mut f: impl FnMut() -> Result<T, E>,
+    ) -> anyhow::Result<T> {
+        static LOGGER: std::sync::Once = std::sync::Once::new();
+        LOGGER.call_once(|| {
+            if log::set_logger(&StderrLog).is_ok() {
+                log::set_max_level(log::LevelFilter::Info);
+            }
+        });
+        let mut delay = std::time::Duration::from_secs_f64(self.retry_delay);
+        let mut retries = self.retries;
+        loop {
+            STDERR.lock().unwrap().clear();
+            let e = match f() {
+                Ok(v) => return Ok(v),
+                Err(e) => e.into(),
+            };
+            let stderr: Vec<String> = STDERR.lock().unwrap().drain(..).collect();
+            let e = match stderr.as_slice() {
+                [] => e,
+                lines => anyhow::anyhow!("{e:#}: {}", lines.join("; ")),
+            };
+            let transient = TRANSIENT.iter().any(|t| format!("{e:#}").contains(t));
+            if !transient || retries == 0 {
+                return Err(e);
+            }
+            retries -= 1;
+            eprintln!("{e:#}; retrying in {delay:?}");
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+}
+
+// iptables itself, retrying changes as the retry policy says
+struct SystemIptables<'a>(&'a RetryPolicy);
+
+impl nat::Iptables for SystemIptables<'_> {
+    fn test(&mut self, args: &[String]) -> bool {
+        run_cmd!(iptables $[args] 2>/dev/null).is_ok()
+    }
+
+    fn run(&mut self, args: &[String]) -> anyhow::Result<()> {
+        self.0.run(|| run_cmd!(iptables $[args]))
+    }
+}
+
+impl SetupLinux {
+    // Reasons not to trust the calculation enough to rebuild routing around it
+    fn doubts(&self, data: &MapEData, rule: &Rule) -> Vec<String> {
+        let mut doubts = vec![];
+        if rule.confidence == Confidence::Inferred {
+            doubts.push(format!(
+                "the rule {rule} is inferred ({}), nobody has reported it working",
+                rule.source
+            ));
+        }
+        if let Some(doubt) = &data.ipv4_doubt {
+            doubts.push(doubt.clone());
+        }
+        // There's no CE address to check, the ISP says what the tunnel address is
+        if rule.fixed() {
+            return doubts;
+        }
+        if let Some(local_addr) = self.local_addr {
+            if local_addr != data.edge_addr {
+                doubts.push(format!(
+                    "the tunnel address {local_addr} overrides the CE address {}",
+                    data.edge_addr
+                ));
+            }
+        }
+        // Decode the CE again independently: it must sit in the user's end-user prefix and embed
+        // the IPv4 address and PSID where the BR expects them
+        let (ce, addr) = (u128::from(data.edge_addr), u128::from(data.addr));
+        let (prefix_len, embedded) = match rule.ce_layout {
+            CeLayout::Jpne => {
+                let segs = data.edge_addr.segments();
+                let ipv4 = ((segs[4] as u32 & 0xff) << 24)
+                    | ((segs[5] as u32) << 8)
+                    | (segs[6] as u32 >> 8);
+                (56, (ipv4, segs[7] >> 8))
+            }
+            CeLayout::Rfc7597 => (
+                rule.bmr.ipv6_len as u32 + rule.bmr.ea_len as u32,
+                ((ce >> 16) as u32, ce as u16),
+            ),
+        };
+        if (ce ^ addr) >> (128 - prefix_len) != 0 {
+            doubts.push(format!(
+                "the CE address {} is outside {}'s /{prefix_len}",
+                data.edge_addr, data.addr
+            ));
+        }
+        if embedded != (u32::from(data.ipv4_addr), data.psid) {
+            doubts.push(format!(
+                "the CE address {} doesn't embed {} and PSID {}",
+                data.edge_addr, data.ipv4_addr, data.psid
+            ));
+        }
+        doubts
+    }
+
+    // Spell out what's about to be changed, since several steps can cut off existing
+    // connectivity, and ask before doing it
+    fn confirm(
+        &self,
+        data: &MapEData,
+        wan_dev: &str,
+        tunnel: &Tunnel,
+        resolvers: &[std::net::Ipv6Addr],
+    ) -> anyhow::Result<()> {
+        println!("About to:");
+        println!("  add {} to {wan_dev}", data.edge_addr);
+        match self.wan6_gateway {
+            Some(gw) => println!(
+                "  route {} via {gw}, and IPv6 by default if {wan_dev} has no default yet",
+                data.br_addr
+            ),
+            None => println!(
+                "  route {} via {wan_dev}'s IPv6 default gateway",
+                data.br_addr
+            ),
+        }
+        match tunnel {
+            Tunnel::Create => println!("  create tunnel {} to {}", self.tun_dev, data.br_addr),
+            Tunnel::Adopt => println!("  reuse the existing tunnel {}", self.tun_dev),
+            Tunnel::Replace(existing) => println!(
+                "  delete the existing {} ({existing}) and recreate it to {}",
+                self.tun_dev, data.br_addr
+            ),
+        }
+        let clashing = self.clashing_defaults()?;
+        if clashing.is_empty() {
+            println!(
+                "  add an IPv4 default route via {}, ahead of any others",
+                self.tun_dev
+            );
+        } else {
+            let clashing: Vec<_> = clashing.iter().map(|r| r.to_string()).collect();
+            println!(
+                "  replace the IPv4 default route ({}) with one via {}",
+                clashing.join(", "),
+                self.tun_dev
+            );
+        }
+        for guest in &self.guest_devs {
+            match self.guest_gateway {
+                Some(gw) => println!("  route IPv4 from {guest} via {gw} instead of the tunnel"),
+                None => println!("  block IPv4 from {guest} from leaving the LAN"),
+            }
+        }
+        if self.nft {
+            println!("  replace the nft table ip v6plus_tun");
+        } else {
+            println!("  flush the entire iptables nat table, including rules from other tools");
+        }
+        println!(
+            "  add HMARK, SNAT and MSS clamping rules for {}",
+            data.ipv4_addr
+        );
+        if !resolvers.is_empty() {
+            let resolvers: Vec<_> = resolvers.iter().map(|r| r.to_string()).collect();
+            println!(
+                "  put whichever of {} answer first in the system resolver's list",
+                resolvers.join(", ")
+            );
+        }
+        print!("Continue? [y/N] ");
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            bail!("aborted");
+        }
+        Ok(())
+    }
+
+    pub fn setup(&self) -> anyhow::Result<()> {
+        // A typo'd interface name would otherwise get as far as reshaping routing. At boot the
+        // device may not be there yet, so this is retried.
+        let (wan_dev, auto_addr) = if self.auto {
+            let (dev, addr) = self
+                .retry
+                .run(|| crate::auto_addr(self.wan_dev.as_deref()))?;
+            (dev, Some(addr))
+        } else {
+            // clap requires --wan without --auto
+            let wan = self.wan_dev.as_deref().unwrap();
+            (self.retry.run(|| iface::resolve_dev(wan))?, None)
+        };
+        let wan_dev = &wan_dev;
+        let lease = self
+            .lease
+            .as_deref()
+            .map(dhcp6::Lease::from_spec)
+            .transpose()?;
+        let addr = match (self.addr.or(auto_addr), &lease) {
+            (Some(addr), _) => addr,
+            (None, Some(lease)) => lease.addr()?,
+            // At boot the WAN often doesn't have its address yet, so this is retried too. With
+            // rules from DHCPv6 any prefix will do.
+            (None, None) => self.retry.run(|| {
+                let dev = match &self.from_interface {
+                    Some(dev) => iface::resolve_dev(dev)?,
+                    None => wan_dev.clone(),
+                };
+                iface::select_wan_addr(&dev, |a| self.dhcpv6 || MapEData::from_addr(a).is_ok())
+            })?,
+        };
+        // The ISP's own MAP-E options win over our table
+        let s46 = match &lease {
+            Some(lease) => lease.rule()?,
+            None if self.dhcpv6 => {
+                let info = dhcp6::request_info(wan_dev, std::time::Duration::from_secs(5))?;
+                let rule = info.rule_for(addr)?;
+                if rule.is_none() {
+                    eprintln!("The DHCPv6 server sent no MAP-E options, using the built-in rules");
+                }
+                rule
+            }
+            None => None,
+        };
+        let (data, rule) = match s46 {
+            Some(rule) => (MapEData::from_rule(addr, &rule)?, rule),
+            // from_addr finding a rule means this does too
+            None => (
+                MapEData::from_addr(addr)?,
+                *crate::rules::lookup(addr).unwrap(),
+            ),
+        };
+        let (tun_dev, br_addr, edge_addr) = (&self.tun_dev, data.br_addr, data.edge_addr);
+        if rule.fixed() && self.local_addr.is_none() {
+            bail!("{rule} is a fixed address rule, which needs --local-addr: the tunnel address your ISP gave you, on the WAN");
+        }
+        // Only the prefix goes into the calculation, so a privacy address there is harmless. As
+        // the tunnel's source it isn't: the kernel retires it within a day, taking the tunnel along.
+        if let Some(local_addr) = self.local_addr {
+            if iface::global_addrs(wan_dev)?
+                .iter()
+                .any(|a| a.addr == local_addr && a.temporary)
+            {
+                bail!("--local-addr {local_addr} is a temporary (privacy) address on {wan_dev}, which is replaced within a day. Give a stable address, or leave --local-addr out to use the CE address");
+            }
+        }
+
+        let doubts = self.doubts(&data, &rule);
+        if !doubts.is_empty() && !self.force {
+            bail!(
+                "refusing to set up, the calculation may be wrong:\n  {}\nPass --i-know-what-im-doing to go ahead anyway",
+                doubts.join("\n  ")
+            );
+        }
+        self.check_hgw(data.ipv4_addr)?;
+        // Bad exclusions or pins should fail before anything is touched
+        let plan = self.nat.plan(&data.port_ranges)?;
+        let fw_rules = self.nat.rules(&plan, data.ipv4_addr);
+        let ruleset = self.nft.then(|| nat::nft(&fw_rules, tun_dev)).transpose()?;
+
+        // A leftover tunnel (e.g. from the bash script) is reused if it's what we'd create anyway.
+        // Anything else has to be dealt with now, not after half the setup is done.
+        let want_local = self.local_addr.unwrap_or(edge_addr);
+        let tunnel = match iface::ip6_tunnel(tun_dev)? {
+            None => Tunnel::Create,
+            Some(t)
+                if t.mode == "ip/ipv6"
+                    && t.remote == Some(br_addr)
+                    && t.local == Some(want_local)
+                    && t.link.as_deref() == Some(wan_dev.as_str()) =>
+            {
+                Tunnel::Adopt
+            }
+            Some(t) if self.replace_tunnel => Tunnel::Replace(t),
+            Some(t) => bail!(
+                "{tun_dev} already exists as {t}, not ip/ipv6 remote {br_addr} local {want_local} dev {wan_dev}. Pass --replace-tunnel to recreate it, or --tun for another name"
+            ),
+        };
+
+        let resolvers = match (&lease, self.resolvers.is_empty()) {
+            _ if !self.set_resolver => vec![],
+            (_, false) => self.resolvers.clone(),
+            (Some(lease), true) if !lease.dns.is_empty() => lease.dns.clone(),
+            _ => bail!("--set-resolver needs --resolver, or a --lease with DNS servers in it"),
+        };
+
+        if !self.yes && std::io::stdin().is_terminal() {
+            self.confirm(&data, wan_dev, &tunnel, &resolvers)?;
+        }
+
+        // This is a copy of a well-known bash script that floats around the internet for people
+        // doing this sorta thing.
+        // Copyright unclear, I'll rewrite this in proper rust eventually, but for now I just want
+        // something that works.
+
+        let local_addr = match self.local_addr {
+            // Unusual setups (the CE on a dummy interface, several prefixes) manage the address
+            // themselves and just tell us which one to use
+            Some(local_addr) => {
+                if local_addr != edge_addr {
+                    eprintln!("Warning: tunnel local address {local_addr} is not the CE address {edge_addr}; the BR may drop our traffic");
+                }
+                local_addr
+            }
+            // Left over from an earlier run, like the tunnel
+            None if iface::global_addrs(wan_dev)?
+                .iter()
+                .any(|a| a.addr == edge_addr && a.usable()) =>
+            {
+                edge_addr
+            }
+            None => {
+                // Unless it failed DAD or never finished it, when the kernel won't use it. Added
+                // again, it gets another go.
+                if let Some(stale) = iface::global_addrs(wan_dev)?
+                    .into_iter()
+                    .find(|a| a.addr == edge_addr)
+                {
+                    eprintln!("{edge_addr} is on {wan_dev} from an earlier run but not usable (failed or unfinished DAD), adding it again");
+                    let len = stale.prefix_len;
+                    self.retry
+                        .run(|| run_cmd!(ip -6 addr del $edge_addr/$len dev $wan_dev))?;
+                }
+                // Add our side of the tunnel to the WAN interface, that's the CE addr
+                if self.nodad {
+                    self.retry
+                        .run(|| run_cmd!(ip -6 addr add $edge_addr dev $wan_dev nodad))?;
+                } else {
+                    self.retry
+                        .run(|| run_cmd!(ip -6 addr add $edge_addr dev $wan_dev))?;
+                    iface::wait_for_dad(wan_dev, edge_addr, std::time::Duration::from_secs(5))?;
+                }
+                if self.announce {
+                    ndisc::announce(wan_dev, edge_addr)?;
+                }
+                edge_addr
+            }
+        };
+        // Send tunnel traffic to the BR out of the WAN explicitly. If the IPv6 default is a
+        // link-local next hop (as on NGN), or another interface also has a default route, relying
+        // on global routing can pick the wrong device or no usable gateway at all.
+        // A static setup (no RA on the WAN) has no default route of its own, so the gateway is given
+        // and we install what RA would have
+        if let Some(gw) = self.wan6_gateway {
+            // A global gateway isn't known to be on-link without a prefix route for it
+            if gw.segments()[0] & 0xffc0 != 0xfe80 {
+                self.retry
+                    .run(|| run_cmd!(ip -6 route replace $gw/128 dev $wan_dev))?;
+            }
+            if iface::default_gateway(wan_dev)?.is_none() {
+                self.retry
+                    .run(|| run_cmd!(ip -6 route add default via $gw dev $wan_dev))?;
+            }
+        }
+        match self.wan6_gateway.map_or_else(|| iface::default_gateway(wan_dev), |gw| Ok(Some(gw)))? {
+            Some(gw) => {
+                self.retry
+                    .run(|| run_cmd!(ip -6 route replace $br_addr/128 via $gw dev $wan_dev))?;
+            }
+            None => eprintln!(
+                "Warning: no IPv6 default gateway on {wan_dev}, relying on existing routes to reach the BR"
+            ),
+        }
+        // Add the tunnel
+        if let Tunnel::Replace(_) = tunnel {
+            self.retry.run(|| run_cmd!(ip link del $tun_dev))?;
+        }
+        if let Tunnel::Adopt = tunnel {
+            eprintln!("{tun_dev} already exists with the same parameters, reusing it");
+        } else {
+            match self.tun_mode.resolve() {
+                TunMode::Ipip6 => self.retry.run(|| run_cmd!(ip link add $tun_dev type ip6tnl mode ipip6 remote $br_addr local $local_addr dev $wan_dev encaplimit none))?,
+                _ => self.retry.run(|| run_cmd!(ip -6 tunnel add $tun_dev mode ip4ip6 remote $br_addr local $local_addr dev $wan_dev encaplimit none))?,
+            }
+        }
+        // TODO: calc mtu from WAN, not from hard coding it
+        self.retry
+            .run(|| run_cmd!(ip link set dev $tun_dev mtu 1460))?;
+
+        // NAT goes in before the tunnel is up and routed, so nothing leaves through it un-SNAT'd.
+        // The first packet of a connection fixes its NAT binding, after which conntrack keeps
+        // sending its packets with the bogus source port the BR drops until the entry expires.
+        if let Some(ruleset) = &ruleset {
+            // The file deletes and recreates our table, and nft applies a file as one
+            // transaction, so the old rules are in force right up until the new ones are. No
+            // packet sees a half-built table, and a file that fails to load changes nothing.
+            self.retry.run(|| nft_load(ruleset))?;
+        } else {
+            // On a rerun the tunnel is already routed, so new connections are dropped (and
+            // retried by their senders) while the nat table is flushed and rebuilt.
+            // If rebuilding fails the guard stays, failing closed. Any copies left by such a run
+            // go first, or the one deleted below would leave them blocking for good.
+            let guard: Vec<String> = format!("-o {tun_dev} -m conntrack --ctstate NEW -j DROP")
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+            for chain in ["FORWARD", "OUTPUT"] {
+                self.delete_all("filter", chain, &guard)?;
+                self.retry.run(|| {
+                    let guard = &guard;
+                    run_cmd!(iptables -I $chain 1 $[guard])
+                })?;
+            }
+            let mut iptables = SystemIptables(&self.retry);
+            nat::install_iptables(&fw_rules, &self.tun_dev, &mut iptables).context(
+                "could not install the NAT rules; new connections through the tunnel are blocked until setup-linux succeeds",
+            )?;
+            for chain in ["FORWARD", "OUTPUT"] {
+                self.retry.run(|| {
+                    let guard = &guard;
+                    run_cmd!(iptables -D $chain $[guard])
+                })?;
+            }
+        }
+        self.retry.run(|| run_cmd!(ip link set dev $tun_dev up))?;
+
+        // all ipv4 goes over the tunnel
+        for route in self.clashing_defaults()? {
+            let dev = route.dev.as_deref().unwrap_or_default();
+            match route.via {
+                Some(via) => self
+                    .retry
+                    .run(|| run_cmd!(ip route del default via $via dev $dev))?,
+                None => self.retry.run(|| run_cmd!(ip route del default dev $dev))?,
+            }
+            if route.proto.as_deref().is_some_and(|p| p != "static") {
+                let fix = match iface::route_manager() {
+                    Some((name, fix)) => format!("{name} will probably put it back; {fix}"),
+                    None => "whatever installed it may put it back".to_string(),
+                };
+                eprintln!(
+                    "Warning: removed the IPv4 default route {route}, {fix}, or give it a metric"
+                );
+            }
+        }
+        self.retry
+            .run(|| run_cmd!(ip route replace default dev $tun_dev metric 0))?;
+
+        // except for guests, so untrusted devices can't use up our few ports. Their traffic is
+        // looked up in a table of its own, which never mentions the tunnel.
+        if !self.guest_devs.is_empty() {
+            let table = self.guest_table;
+            match self.guest_gateway {
+                Some(gw) => self
+                    .retry
+                    .run(|| run_cmd!(ip route replace default via $gw table $table))?,
+                None => self
+                    .retry
+                    .run(|| run_cmd!(ip route replace unreachable default table $table))?,
+            }
+        }
+        // The kernel refuses a rule that's already there, and guests dropped from the command line
+        // would keep their rules for good
+        let table = self.guest_table;
+        let existing = iface::iif_rules(table)?;
+        for dev in existing.iter().filter(|d| !self.guest_devs.contains(d)) {
+            self.retry
+                .run(|| run_cmd!(ip rule del iif $dev lookup $table))?;
+        }
+        for guest in self.guest_devs.iter().filter(|g| !existing.contains(g)) {
+            self.retry
+                .run(|| run_cmd!(ip rule add iif $guest lookup $table))?;
+        }
+
+        if !resolvers.is_empty() {
+            self.set_resolver(wan_dev, &resolvers)?;
+        }
+
+        let end_user = ipnet::Ipv6Net::new(data.addr, rule.bmr.ipv6_len + rule.bmr.ea_len)
+            .unwrap()
+            .trunc();
+        let new_tunnel = !matches!(tunnel, Tunnel::Adopt);
+        if let Err(e) = stats::record(&self.state_dir, tun_dev, end_user, new_tunnel) {
+            eprintln!("Warning: could not update the statistics: {e:#}");
+        }
+
+        if let Some(listen) = self.health_listen {
+            let ipv4_addr = data.ipv4_addr;
+            health::serve(listen, |ready| {
+                self.health(wan_dev, local_addr, ipv4_addr, ready)
+            })?;
+        }
+        Ok(())
+    }
+
+    // An HGW already terminating MAP-E has the same CE address and IPv4 address and ports as we'd
+    // set up, so once both are at it neither works: the CE address fails DAD or flaps between the
+    // two, and each resets the other's connections. IPv4 going out as our address through the
+    // current default (not our tunnel, which a re-run would probe through) gives that away.
+    fn check_hgw(&self, ipv4_addr: std::net::Ipv4Addr) -> anyhow::Result<()> {
+        let Some(route) = iface::ipv4_default_routes()?
+            .into_iter()
+            .min_by_key(|r| r.metric)
+        else {
+            return Ok(());
+        };
+        if route.dev.as_deref() == Some(self.tun_dev.as_str()) {
+            return Ok(());
+        }
+        match crate::detect_ipv4() {
+            Ok(seen) if seen == ipv4_addr && self.ignore_hgw => {
+                eprintln!("Warning: IPv4 already goes out as {seen} through {route}, so something (most likely the HGW) is terminating MAP-E too; going ahead as --ignore-hgw was given");
+            }
+            Ok(seen) if seen == ipv4_addr => bail!(
+                "IPv4 already goes out as {seen}, the calculated address, through {route}: something on the way, most likely the HGW, is terminating MAP-E itself. Setting up as well would break IPv4 for both. Turn off the HGW's \"IPv4 over IPv6\" setting (the v6plus/MAP-E connection, not IPv6 itself) and restart it, check IPv4 has stopped working here, then set up again. Pass --ignore-hgw to take over the default route anyway"
+            ),
+            Ok(seen) => eprintln!(
+                "IPv4 goes out as {seen} through {route} for now, which isn't MAP-E with our address ({ipv4_addr}), so it's moved over to the tunnel"
+            ),
+            Err(_) => {}
+        }
+        Ok(())
+    }
+
+    // Ours goes in at metric 0, ahead of any others, which can stay (e.g. a DHCP client's, as a
+    // fallback). Only others at metric 0 clash with it, and have to go.
+    fn clashing_defaults(&self) -> anyhow::Result<Vec<iface::DefaultRoute>> {
+        Ok(iface::ipv4_default_routes()?
+            .into_iter()
+            .filter(|r| r.metric == 0 && r.dev.as_deref() != Some(self.tun_dev.as_str()))
+            .collect())
+    }
+
+    // NXDOMAIN for everything (or nothing at all) looks just like a broken tunnel, so only
+    // resolvers that answer go in. systemd-resolved gets them as the WAN's, anything else at the top
+    // of resolv.conf, ahead of the nameservers already there.
+    fn set_resolver(&self, wan_dev: &str, resolvers: &[std::net::Ipv6Addr]) -> anyhow::Result<()> {
+        let mut working = vec![];
+        for &resolver in resolvers {
+            match dns::check(resolver, "example.com", std::time::Duration::from_secs(2)) {
+                Ok(_) => working.push(resolver),
+                Err(e) => eprintln!("Warning: leaving out {resolver}: {e:#}"),
+            }
+        }
+        if working.is_empty() {
+            bail!("none of the resolvers answered, so the system resolver was left alone");
+        }
+        let resolv_conf = std::path::Path::new("/etc/resolv.conf");
+        let managed = std::fs::read_link(resolv_conf)
+            .is_ok_and(|target| target.to_string_lossy().contains("systemd"));
+        if managed {
+            let addrs: Vec<_> = working.iter().map(|r| r.to_string()).collect();
+            return self.retry.run(|| {
+                let addrs = &addrs;
+                run_cmd!(resolvectl dns $wan_dev $[addrs])
+            });
+        }
+        let existing = std::fs::read_to_string(resolv_conf).unwrap_or_default();
+        let mut out: String = working
+            .iter()
+            .map(|r| format!("nameserver {r}\n"))
+            .collect();
+        for line in existing.lines() {
+            let ours = line
+                .strip_prefix("nameserver")
+                .and_then(|a| a.trim().parse().ok())
+                .is_some_and(|a: std::net::Ipv6Addr| working.contains(&a));
+            if !ours {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+        std::fs::write(resolv_conf, out).context("could not write /etc/resolv.conf")
+    }
+
+    // -D removes one copy of a rule at a time
+    fn delete_all(&self, table: &str, chain: &str, rule: &[String]) -> anyhow::Result<()> {
+        while run_cmd!(iptables -t $table -C $chain $[rule] 2>/dev/null).is_ok() {
+            self.retry
+                .run(|| run_cmd!(iptables -t $table -D $chain $[rule]))?;
+        }
+        Ok(())
+    }
+
+    // Live while the tunnel is up on our address, ready while IPv4 is also routed and SNAT'd
+    // through it. Other tools (or a WAN flap) can undo any of these behind our back.
+    fn health(
+        &self,
+        wan_dev: &str,
+        local_addr: std::net::Ipv6Addr,
+        ipv4_addr: std::net::Ipv4Addr,
+        ready: bool,
+    ) -> Result<(), String> {
+        let tun_dev = &self.tun_dev;
+        let flags = std::fs::read_to_string(format!("/sys/class/net/{tun_dev}/flags"))
+            .map_err(|_| format!("{tun_dev} is gone"))?;
+        // IFF_UP
+        if u32::from_str_radix(flags.trim().trim_start_matches("0x"), 16).unwrap_or(0) & 1 == 0 {
+            return Err(format!("{tun_dev} is down"));
+        }
+        let addrs = iface::global_addrs(wan_dev).map_err(|e| format!("{e:#}"))?;
+        if self.local_addr.is_none() && !addrs.iter().any(|a| a.addr == local_addr) {
+            return Err(format!("{local_addr} is no longer on {wan_dev}"));
+        }
+        if !ready {
+            return Ok(());
+        }
+        // Other defaults can stay, but ours has to be the one in use, the first listed
+        let default = run_fun!(ip route show default).unwrap_or_default();
+        if !default
+            .lines()
+            .next()
+            .is_some_and(|l| l.contains(&format!("dev {tun_dev}")))
+        {
+            return Err(format!("the IPv4 default route is not via {tun_dev}"));
+        }
+        let snat = if self.nft {
+            run_fun!(nft list table ip v6plus_tun)
+                .map_err(|e| e.to_string())?
+                .contains(&format!("snat to {ipv4_addr}"))
+        } else {
+            run_fun!(iptables -t nat -S POSTROUTING)
+                .map_err(|e| e.to_string())?
+                .contains(&format!("--to-source {ipv4_addr}"))
+        };
+        if !snat {
+            return Err(format!("no SNAT to {ipv4_addr} in the nat table"));
+        }
+        Ok(())
+    }
+}
+
+fn nft_load(ruleset: &str) -> anyhow::Result<()> {
+    let mut child = std::process::Command::new("nft")
+        .args(["-f", "-"])
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+        .context("could not run nft")?;
+    // Closed once written, so nft sees the end of the file
+    child.stdin.take().unwrap().write_all(ruleset.as_bytes())?;
+    if !child.wait()?.success() {
+        bail!("nft could not load the rules");
+    }
+    Ok(())
+}
+
+#[derive(Parser)]
+pub struct WaitForPrefix {
+    #[arg(
+        long = "wan",
+        required = true,
+        help = "WAN interface device, such as 'eth0', 'mac:52:54:00:12:34:56' or 'prefix:240b:10::/31' for whichever holds an address in it"
+    )]
+    wan_dev: String,
+    #[arg(long, default_value_t = 120, help = "Seconds to wait before giving up")]
+    timeout: u64,
+}
+
+impl WaitForPrefix {
+    // Meant for ExecStartPre= in a unit running setup-linux, instead of a sleep loop
+    pub fn wait(&self) -> anyhow::Result<()> {
+        let found = iface::wait_for_prefix(
+            &self.wan_dev,
+            std::time::Duration::from_secs(self.timeout),
+            |a| MapEData::from_addr(a).is_ok(),
+        )?;
+        println!("{}/{}", found.addr, found.prefix_len);
+        Ok(())
+    }
+}
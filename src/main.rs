@@ -1,204 +1,1623 @@
-use anyhow::bail;
+use std::ffi::OsString;
+use std::net::ToSocketAddrs;
+
+use anyhow::{bail, Context};
 use clap::{Parser, Subcommand};
-use cmd_lib::run_cmd;
+use v6plus_tun::mape::{Fmr, MapEData};
+use v6plus_tun::nat::{self, parse_pin, parse_port_range, FwRule, Pin, SnatPlan};
+use v6plus_tun::ports::PortSet;
+use v6plus_tun::rules;
+
+#[cfg(target_os = "linux")]
+mod audit;
+mod dhcp6;
+#[cfg(target_os = "linux")]
+mod dns;
+#[cfg(target_os = "linux")]
+mod doctor;
+mod export;
+mod forwards;
+#[cfg(target_os = "linux")]
+mod health;
+#[cfg(target_os = "linux")]
+mod iface;
+#[cfg(target_os = "linux")]
+mod lab;
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+mod ndisc;
+mod schema;
+#[cfg(target_os = "linux")]
+mod stats;
+#[cfg(target_os = "linux")]
+mod top;
+#[cfg(target_os = "linux")]
+mod traffic;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
     #[command(subcommand)]
     sub: Subcommands,
+    #[arg(
+        long,
+        global = true,
+        help = "TOML (or .json) file of extra mapping rules, which override built-in ones for the same prefix"
+    )]
+    rules: Option<std::path::PathBuf>,
+}
+
+// How the address to calculate from is given, shared by every subcommand that calculates
+#[derive(clap::Args)]
+struct AddrArgs {
+    #[arg(
+        required_unless_present_any = ["detect_addr", "lease", "from_interface", "from_ra", "auto", "seen"],
+        help = "IPv6 address, delegated prefix such as 2404:7a80:1234:5600::/56, or a hostname whose AAAA record should be used; for calculate, '-' reads one per line from stdin"
+    )]
+    addr: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "lease", "from_interface", "from_ra", "auto"],
+        help = "Use this machine's global IPv6 address, as seen by an external resolver"
+    )]
+    detect_addr: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "lease"],
+        help = "Use a global IPv6 address of this interface (Linux only), skipping ULA and temporary addresses"
+    )]
+    from_interface: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "lease", "from_interface"],
+        help = "Solicit a router advertisement on this interface and use its prefix (Linux only, needs root)"
+    )]
+    from_ra: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "lease", "from_interface", "from_ra"],
+        help = "Find the WAN by the IPv6 default route, and the prefix by RA, DHCPv6 lease or the WAN's addresses, in that order (Linux only)"
+    )]
+    auto: bool,
+    #[arg(
+        long,
+        visible_alias = "from-lease",
+        conflicts_with = "addr",
+        help = "DHCPv6 client state to take the delegated prefix (and MAP-E options, if any) from: dhcpcd, odhcp6c or wide-dhcpv6, optionally with :PATH, or a dhcpcd lease file"
+    )]
+    lease: Option<String>,
+    #[arg(
+        long,
+        conflicts_with_all = ["addr", "detect_addr", "lease", "from_interface", "from_ra", "auto"],
+        help = "IPv6 address a check site (such as check.xpass.jp) reported, which may be the HGW's own rather than this machine's; it's checked for being usable first"
+    )]
+    seen: Option<std::net::Ipv6Addr>,
 }
 
 #[derive(Parser)]
 struct Calculate {
-    #[arg(required = true)]
-    addr: std::net::Ipv6Addr,
-}
-
-#[derive(Debug)]
-struct MapEData {
-    addr: std::net::Ipv6Addr,
-    ipv4_addr: std::net::Ipv4Addr,
-    br_addr: std::net::Ipv6Addr,
-    edge_addr: std::net::Ipv6Addr,
-    psid: u8,
-    port_ranges: Vec<(u16, u16)>,
-}
-
-impl std::fmt::Display for MapEData {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "IPv4 Addr (CE IPv4 Address): {}", self.ipv4_addr)?;
-        writeln!(f, "CE IPv6 Addr: {}", self.edge_addr)?;
-        writeln!(
-            f,
-            "Port Ranges: {}",
-            self.port_ranges
-                .iter()
-                .map(|el| format!("{}-{}", el.0, el.1))
-                .collect::<Vec<_>>()
-                .join(", ")
-        )?;
-        writeln!(f, "PSID: {}", self.psid)?;
-        writeln!(f, "Border Relay Address (BR Address): {}", self.br_addr)
-    }
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long,
+        help = "Also list every owned port, flagging ones used by well-known services"
+    )]
+    list_ports: bool,
+    #[arg(
+        long,
+        conflicts_with = "output",
+        help = "Print the parameters as a table"
+    )]
+    table: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Output::Text,
+        conflicts_with_all = ["list_ports", "probe_br"],
+        help = "Output format"
+    )]
+    output: Output,
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["output", "table", "list_ports"],
+        help = "Print only the owned ports, in this format, for feeding to other tools"
+    )]
+    ports: Option<PortFormat>,
+    #[arg(long, help = "Also copy the output to the clipboard")]
+    copy: bool,
+    #[arg(
+        long,
+        help = "Also ping and traceroute the BR over IPv6, to check it's plausible before setting up"
+    )]
+    probe_br: bool,
+    #[arg(
+        long,
+        conflicts_with = "lease",
+        help = "Number of embedded address (EA) bits, instead of the rule's (calculate-generic needs this or --psid-len)"
+    )]
+    ea_len: Option<u8>,
+    #[arg(
+        long,
+        conflicts_with_all = ["ea_len", "lease"],
+        help = "Number of PSID bits, instead of the rule's; the EA bits are the IPv4 suffix's plus these"
+    )]
+    psid_len: Option<u8>,
+    #[arg(
+        long,
+        conflicts_with = "lease",
+        help = "PSID offset, instead of the rule's (6 by default for calculate-generic)"
+    )]
+    psid_offset: Option<u8>,
+    #[arg(
+        long,
+        requires_all = ["ipv4_prefix", "br"],
+        conflicts_with = "lease",
+        help = "Calculate with --ipv4-prefix and --br under a v6plus-style /32 rule of the address's, for a prefix not in the table. Nothing about the result is verified"
+    )]
+    force: bool,
+    #[arg(
+        long,
+        requires = "force",
+        help = "IPv4 prefix the address's /32 maps onto, for --force"
+    )]
+    ipv4_prefix: Option<ipnet::Ipv4Net>,
+    #[arg(long, help = "BR address, for --force (and calculate-generic)")]
+    br: Option<std::net::Ipv6Addr>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Output {
+    Text,
+    /// The same fields plugins get on stdin
+    Json,
+    Yaml,
+    /// MAPE_* shell variable assignments, for `eval`
+    Env,
+    /// Exported V6PLUS_* shell variables, for `eval` in scripts written around the bash script
+    Sh,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum PortFormat {
+    /// `iptables -m multiport --ports` arguments, a line per rule as each takes at most 15 ports
+    /// (a range counting as two)
+    Multiport,
+    /// Every port, comma-separated
+    List,
+    /// How many ports there are
+    Count,
+    /// An nft anonymous set
+    Nft,
 }
 
 impl Calculate {
-    fn calculate(&self) -> anyhow::Result<MapEData> {
-        let v6_segs = self.addr.segments();
-        // Base mapping rules I think? Pulled from ~the internet~
-        let ipv4_prefix = match (v6_segs[0], v6_segs[1]) {
-            (0x2404, 0x7a80) => (133, 200),
-            (0x2404, 0x7a84) => (133, 206),
-            (0x240b, 0x10) => (106, 72),
-            (0x240b, 0x11) => (106, 73),
-            (0x240b, 0x12) => (14, 8),
-            (0x240b, 0x250) => (14, 10),
-            (0x240b, 0x251) => (14, 11),
-            (0x240b, 0x252) => (14, 12),
-            (0x240b, 0x253) => (14, 13),
-            (a, b) => {
-                bail!("unknown prefix: {:x}:{:x}", a, b);
+    fn run(&self) -> anyhow::Result<()> {
+        if self.br.is_some() && !self.force {
+            bail!("--br is for --force, a known prefix's rule has its own BR");
+        }
+        if let (true, Some(ipv4_prefix), Some(br)) = (self.force, self.ipv4_prefix, self.br) {
+            eprintln!("WARNING: --force: NONE OF THIS IS VERIFIED. It assumes a v6plus-style rule from the address's /32 onto {ipv4_prefix}, with BR {br}. If either is wrong, the IPv4 address and ports are someone else's, or nobody's: check them with your ISP before setting up with them");
+        }
+        if self.input.addr.as_deref() == Some("-") {
+            return self.batch();
+        }
+        self.print(&self.calculate(&self.input)?)
+    }
+
+    // The EA bits asked for, if any, for a rule onto an IPv4 prefix of this length
+    fn ea_len(&self, ipv4_len: u8) -> Option<u8> {
+        self.ea_len
+            .or_else(|| Some(self.psid_len?.saturating_add(32 - ipv4_len)))
+    }
+
+    // With the matched rule's PSID parameters replaced by any given on the command line, for
+    // deployments sharing addresses differently from the rules we know
+    fn calculate(&self, input: &AddrArgs) -> anyhow::Result<MapEData> {
+        if self.ea_len.is_none()
+            && self.psid_len.is_none()
+            && self.psid_offset.is_none()
+            && !self.force
+        {
+            return input.calculate();
+        }
+        let addr = input.resolve()?;
+        let mut rule = match (self.ipv4_prefix, self.br) {
+            (Some(ipv4_prefix), Some(br_addr)) if self.force => {
+                self.forced_rule(addr, ipv4_prefix, br_addr)
+            }
+            _ => {
+                // from_addr explains an unknown prefix, and it finding a rule means lookup does too
+                MapEData::from_addr(addr)?;
+                *rules::lookup(addr).unwrap()
             }
         };
+        rule.bmr.ea_len = self.ea_len(rule.bmr.ipv4_len).unwrap_or(rule.bmr.ea_len);
+        rule.bmr.psid_offset = self.psid_offset.unwrap_or(rule.bmr.psid_offset);
+        rule.bmr.validate().map_err(anyhow::Error::msg)?;
+        let shape = (rule.bmr.ipv6_len, rule.bmr.ipv4_len, rule.bmr.psid_len());
+        if rule.ce_layout == rules::CeLayout::Jpne && shape != (32, 16, 8) {
+            eprintln!(
+                "Note: JPNE's CE address layout only exists for 8-bit PSIDs, using RFC 7597's"
+            );
+            rule.ce_layout = rules::CeLayout::Rfc7597;
+        }
+        input.check_prefix(&rule.bmr)?;
+        MapEData::from_rule(addr, &rule)
+    }
 
-        let v6_octets = self.addr.octets();
-        let psid = v6_octets[6];
-        // the last two octets of the map-e v4 address are just taken from the v6 address's 3rd
-        // segment
-        let ipv4_addr =
-            std::net::Ipv4Addr::new(ipv4_prefix.0, ipv4_prefix.1, v6_octets[4], v6_octets[5]);
-        let ipv4_octets = ipv4_addr.octets();
-
-        let ce = std::net::Ipv6Addr::new(
-            v6_segs[0],
-            v6_segs[1],
-            ((ipv4_octets[2] as u16) << 8) + ipv4_octets[3] as u16,
-            (psid as u16) << 8,
-            ipv4_octets[0] as u16,
-            ((ipv4_octets[1] as u16) << 8) + ipv4_octets[2] as u16,
-            (ipv4_octets[3] as u16) << 8,
-            (psid as u16) << 8,
-        );
+    // What JPNE's rules look like, a /32 with 8 PSID bits at offset 4, onto the given IPv4 prefix
+    fn forced_rule(
+        &self,
+        addr: std::net::Ipv6Addr,
+        ipv4_prefix: ipnet::Ipv4Net,
+        br_addr: std::net::Ipv6Addr,
+    ) -> rules::Rule {
+        let ipv6_prefix = ipnet::Ipv6Net::new(addr, 32).unwrap().network();
+        let ipv4_len = ipv4_prefix.prefix_len();
+        rules::Rule {
+            bmr: v6plus_tun::Bmr {
+                ipv6_prefix,
+                ipv6_len: 32,
+                ipv4_prefix: ipv4_prefix.network(),
+                ipv4_len,
+                ea_len: (32 - ipv4_len) + 8,
+                psid_offset: 4,
+            },
+            br_addr,
+            ce_layout: rules::CeLayout::Jpne,
+            source: "--force",
+            confidence: rules::Confidence::Inferred,
+        }
+    }
 
-        let prefix31: u32 = self
-            .addr
-            .segments()
-            .into_iter()
-            .take(2)
-            .map(|el| el as u32)
-            .reduce(|l, r| (l << 16) + (r & 0xfffe))
-            .unwrap();
-        let br_addr = if (0x24047a80..0x24047a84).contains(&prefix31) {
-            std::net::Ipv6Addr::new(0x2001, 0x260, 0x700, 0x1, 0, 0, 0x1, 0x275)
-        } else if (0x24047a84..0x24047a88).contains(&prefix31) {
-            std::net::Ipv6Addr::new(0x2001, 0x260, 0x700, 0x1, 0, 0, 0x1, 0x276)
-        } else if (0x240b0010..0x240b0014).contains(&prefix31)
-            || (0x240b0250..0x240b0254).contains(&prefix31)
-        {
-            std::net::Ipv6Addr::new(0x2404, 0x9200, 0x225, 0x100, 0, 0, 0, 0x64)
+    // `calculate -`: an address, prefix or hostname per line of stdin, and a line of tab-separated
+    // results for each. A line that fails is reported on stderr and the rest carry on.
+    fn batch(&self) -> anyhow::Result<()> {
+        if self.table || self.copy || self.list_ports || self.probe_br || self.ports.is_some() {
+            bail!(
+                "--table, --copy, --list-ports, --probe-br and --ports don't work with addresses from stdin"
+            );
+        }
+        if matches!(self.output, Output::Env | Output::Sh) {
+            bail!("--output env and sh are for a single address, the variables would overwrite each other");
+        }
+        let mut failed = 0;
+        for line in std::io::stdin().lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let input = AddrArgs {
+                addr: Some(line.to_string()),
+                detect_addr: false,
+                from_interface: None,
+                from_ra: None,
+                auto: false,
+                lease: None,
+                seen: None,
+            };
+            match self.calculate(&input) {
+                Ok(data) => {
+                    if let Some(doubt) = &data.ipv4_doubt {
+                        eprintln!("{line}: warning: {doubt}");
+                    }
+                    match self.output {
+                        Output::Text => println!("{line}\t{}", tsv(&data)),
+                        // JSON lines, and a YAML document per address
+                        Output::Json => println!("{}", serde_json::to_string(&data)?),
+                        Output::Yaml => print!("---\n{}", to_yaml(&serde_json::to_value(&data)?)),
+                        Output::Env | Output::Sh => unreachable!(),
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{line}: {e:#}");
+                    failed += 1;
+                }
+            }
+        }
+        if failed > 0 {
+            bail!("{failed} addresses failed");
+        }
+        Ok(())
+    }
+
+    fn print(&self, data: &MapEData) -> anyhow::Result<()> {
+        if let Some(doubt) = &data.ipv4_doubt {
+            eprintln!("Warning: {doubt}. Check the address (or rule) you gave");
+        }
+        let out = match (self.ports, self.output) {
+            (Some(format), _) => port_list(data, format),
+            _ if self.table => params_table(data),
+            (_, Output::Text) => format!("{data}\n"),
+            (_, Output::Json) => format!("{}\n", serde_json::to_string_pretty(data)?),
+            (_, Output::Yaml) => to_yaml(&serde_json::to_value(data)?),
+            (_, Output::Env) => shell_vars(data, "", "MAPE_", "PORTS"),
+            (_, Output::Sh) => shell_vars(data, "export ", "V6PLUS_", "PORT_RANGES"),
+        };
+        print!("{out}");
+        if self.copy {
+            copy_to_clipboard(&out)?;
+        }
+        if self.list_ports {
+            print_ports(data);
+        }
+        if self.probe_br {
+            probe_br(data.br_addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct CalculateGeneric {
+    #[command(flatten)]
+    calc: Calculate,
+    #[arg(
+        long,
+        required = true,
+        help = "Rule IPv6 prefix, such as '2001:db8::/40'"
+    )]
+    rule_prefix: ipnet::Ipv6Net,
+    #[arg(
+        long,
+        required = true,
+        help = "Rule IPv4 prefix, such as '192.0.2.0/24'"
+    )]
+    rule_ipv4: ipnet::Ipv4Net,
+}
+
+impl CalculateGeneric {
+    // The standard RFC 7597 algorithm with a rule given on the command line, for MAP-E ISPs other
+    // than JPNE. The CE address uses the RFC's interface ID, not v6plus' older layout.
+    fn run(&self) -> anyhow::Result<()> {
+        let Some(ea_len) = self.calc.ea_len(self.rule_ipv4.prefix_len()) else {
+            bail!("the rule needs --ea-len or --psid-len");
+        };
+        let bmr = v6plus_tun::Bmr {
+            ipv6_prefix: self.rule_prefix.network(),
+            ipv6_len: self.rule_prefix.prefix_len(),
+            ipv4_prefix: self.rule_ipv4.network(),
+            ipv4_len: self.rule_ipv4.prefix_len(),
+            ea_len,
+            psid_offset: self.calc.psid_offset.unwrap_or(6),
+        };
+        if self.calc.force {
+            bail!("calculate-generic's rule is the one given, there's nothing to --force");
+        }
+        let Some(br) = self.calc.br else {
+            bail!("calculate-generic needs --br");
+        };
+        bmr.validate().map_err(anyhow::Error::msg)?;
+        let addr = self.calc.input.resolve()?;
+        self.calc.input.check_prefix(&bmr)?;
+        let Some(mapping) = bmr.apply(addr) else {
+            bail!("{addr} is not in {}", self.rule_prefix);
+        };
+        let ce = mapping.ce;
+        self.calc.print(&MapEData::new(addr, &bmr, mapping, ce, br))
+    }
+}
+
+// Assignments for `eval` in a shell script, each after `keyword` (e.g. "export "). Port ranges are
+// space separated, for looping over.
+fn shell_vars(data: &MapEData, keyword: &str, prefix: &str, ports: &str) -> String {
+    let ranges: Vec<String> = data
+        .port_ranges
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect();
+    [
+        ("ADDR", data.addr.to_string()),
+        ("IPV4", data.ipv4_addr.to_string()),
+        ("CE", data.edge_addr.to_string()),
+        ("BR", data.br_addr.to_string()),
+        ("PSID", data.psid.to_string()),
+        (ports, ranges.join(" ")),
+    ]
+    .iter()
+    .map(|(name, value)| format!("{keyword}{prefix}{name}={}\n", sh_quote(value)))
+    .collect()
+}
+
+// Single quotes keep everything literal, apart from single quotes themselves
+fn sh_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn port_list(data: &MapEData, format: PortFormat) -> String {
+    let ports = PortSet::new(data.port_ranges.iter().copied());
+    let range = |(start, end): (u16, u16), sep: &str| {
+        if start == end {
+            start.to_string()
         } else {
-            bail!("unrecognized prefix");
+            format!("{start}{sep}{end}")
+        }
+    };
+    match format {
+        PortFormat::Multiport => {
+            let mut lines = vec![];
+            let (mut line, mut used) = (vec![], 0);
+            for &(start, end) in ports.ranges() {
+                let cost = if start == end { 1 } else { 2 };
+                if used + cost > 15 {
+                    lines.push(std::mem::take(&mut line).join(","));
+                    used = 0;
+                }
+                line.push(range((start, end), ":"));
+                used += cost;
+            }
+            lines.push(line.join(","));
+            lines.iter().map(|l| format!("{l}\n")).collect()
+        }
+        PortFormat::List => {
+            let list: Vec<String> = ports.iter().map(|p| p.to_string()).collect();
+            format!("{}\n", list.join(","))
+        }
+        PortFormat::Count => format!("{}\n", ports.len()),
+        PortFormat::Nft => {
+            let elements: Vec<String> = ports.ranges().iter().map(|&r| range(r, "-")).collect();
+            format!("{{ {} }}\n", elements.join(", "))
+        }
+    }
+}
+
+// IPv4, CE, PSID, BR and port ranges
+fn tsv(data: &MapEData) -> String {
+    let ranges: Vec<String> = data
+        .port_ranges
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect();
+    format!(
+        "{}\t{}\t{}\t{}\t{}",
+        data.ipv4_addr,
+        data.edge_addr,
+        data.psid,
+        data.br_addr,
+        ranges.join(",")
+    )
+}
+
+fn params_table(data: &MapEData) -> String {
+    let mut rows = vec![
+        ("IPv6 Addr", data.addr.to_string()),
+        ("IPv4 Addr (CE IPv4 Address)", data.ipv4_addr.to_string()),
+        ("CE IPv6 Addr", data.edge_addr.to_string()),
+        ("PSID", data.psid.to_string()),
+        ("Border Relay Address", data.br_addr.to_string()),
+        (
+            "Rule",
+            format!("{} -> {}", data.rule_prefix, data.rule_ipv4_prefix),
+        ),
+        ("EA Bits", data.ea_bits()),
+        (
+            "PSID Offset/Length",
+            format!("{}/{}", data.psid_offset, data.psid_len),
+        ),
+        ("Share Ratio", format!("1:{}", data.share_ratio)),
+        ("DMR", data.dmr.to_string()),
+    ];
+    if data.fmrs.is_empty() {
+        rows.push(("FMRs", "none".to_string()));
+    }
+    for (i, fmr) in data.fmrs.iter().enumerate() {
+        rows.push((if i == 0 { "FMRs" } else { "" }, fmr.to_string()));
+    }
+    for (i, (start, end)) in data.port_ranges.iter().enumerate() {
+        let name = if i == 0 { "Port Ranges" } else { "" };
+        rows.push((name, format!("{start}-{end}")));
+    }
+    table(&rows)
+}
+
+fn table(rows: &[(&str, String)]) -> String {
+    let w0 = rows.iter().map(|r| r.0.len()).max().unwrap_or(0);
+    let w1 = rows.iter().map(|r| r.1.len()).max().unwrap_or(0);
+    let sep = format!("+-{}-+-{}-+\n", "-".repeat(w0), "-".repeat(w1));
+    let mut out = sep.clone();
+    for (name, value) in rows {
+        out += &format!("| {name:w0$} | {value:w1$} |\n");
+    }
+    out += &sep;
+    out
+}
+
+// Block-style YAML for JSON-shaped data, with lists of scalars (like a port range) inline. Strings
+// are always quoted, as JSON strings are valid YAML and IPv6 addresses would otherwise trip over
+// YAML's use of ':'.
+fn to_yaml(value: &serde_json::Value) -> String {
+    fn flow(value: &serde_json::Value) -> Option<String> {
+        match value {
+            serde_json::Value::Array(items) => {
+                let items = items
+                    .iter()
+                    .map(|v| (!v.is_array() && !v.is_object()).then(|| v.to_string()))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(format!("[{}]", items.join(", ")))
+            }
+            serde_json::Value::Object(map) if map.is_empty() => Some("{}".to_string()),
+            serde_json::Value::Object(_) => None,
+            scalar => Some(scalar.to_string()),
+        }
+    }
+    fn block(value: &serde_json::Value, indent: usize, out: &mut String) {
+        let pad = " ".repeat(indent);
+        let entries: Vec<(String, &serde_json::Value)> = match value {
+            serde_json::Value::Object(map) => {
+                map.iter().map(|(k, v)| (format!("{k}:"), v)).collect()
+            }
+            serde_json::Value::Array(items) => items.iter().map(|v| ("-".to_string(), v)).collect(),
+            _ => return out.push_str(&format!("{pad}{}\n", flow(value).unwrap())),
+        };
+        for (key, value) in entries {
+            match flow(value) {
+                Some(v) => out.push_str(&format!("{pad}{key} {v}\n")),
+                None => {
+                    out.push_str(&format!("{pad}{key}\n"));
+                    block(value, indent + 2, out);
+                }
+            }
+        }
+    }
+    let mut out = String::new();
+    block(value, 0, &mut out);
+    out
+}
+
+// Pipe text to whatever clipboard tool the platform has
+fn copy_to_clipboard(text: &str) -> anyhow::Result<()> {
+    let candidates: &[(&str, &[&str])] = if cfg!(target_os = "macos") {
+        &[("pbcopy", &[])]
+    } else if cfg!(windows) {
+        &[("clip", &[])]
+    } else {
+        &[
+            ("wl-copy", &[]),
+            ("xclip", &["-selection", "clipboard"]),
+            ("xsel", &["--clipboard", "--input"]),
+        ]
+    };
+    for (cmd, args) in candidates {
+        let Ok(mut child) = std::process::Command::new(cmd)
+            .args(*args)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+        else {
+            continue;
         };
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), text.as_bytes())?;
+        if child.wait()?.success() {
+            eprintln!("Copied to clipboard");
+            return Ok(());
+        }
+    }
+    bail!("no clipboard tool found");
+}
 
-        let data = MapEData {
-            addr: self.addr,
-            ipv4_addr,
-            // Also called "CE"
-            edge_addr: ce,
-            psid,
-            br_addr,
-            port_ranges: (1..=15)
-                .map(|i| {
-                    (
-                        (i << 12) + ((psid as u16) << 4),
-                        ((i << 12) + ((psid as u16) << 4) + 0xf),
-                    )
-                })
-                .collect(),
+// Ping the BR, then count the hops to it. Only reachability is checked: BRs answer pings, but a
+// working tunnel also needs our CE address on the WAN, which needs setting up first.
+fn probe_br(br: std::net::Ipv6Addr) -> anyhow::Result<()> {
+    let br = br.to_string();
+    let (ping, ping_args, traceroute, traceroute_args): (_, &[&str], _, &[&str]) = if cfg!(windows)
+    {
+        (
+            "ping",
+            &["-6", "-n", "3"],
+            "tracert",
+            &["-6", "-d", "-h", "30"],
+        )
+    } else if cfg!(target_os = "macos") {
+        (
+            "ping6",
+            &["-c", "3"],
+            "traceroute6",
+            &["-n", "-q", "1", "-m", "30"],
+        )
+    } else {
+        (
+            "ping",
+            &["-6", "-c", "3"],
+            "traceroute",
+            &["-6", "-n", "-q", "1", "-m", "30"],
+        )
+    };
+
+    let out = std::process::Command::new(ping)
+        .args(ping_args)
+        .arg(&br)
+        .output()
+        .with_context(|| format!("could not run {ping}"))?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    // e.g. "3 packets transmitted, 3 received, 0% packet loss, time 2003ms"
+    let summary = stdout
+        .lines()
+        .find(|l| l.contains("acket"))
+        .unwrap_or_default()
+        .trim();
+    if out.status.success() {
+        println!("BR {br} answers pings ({summary})");
+    } else {
+        println!("BR {br} doesn't answer pings ({summary})");
+    }
+
+    let out = match std::process::Command::new(traceroute)
+        .args(traceroute_args)
+        .arg(&br)
+        .output()
+    {
+        Ok(out) => out,
+        Err(e) => {
+            println!("could not run {traceroute} to count hops: {e}");
+            return Ok(());
+        }
+    };
+    // Hop lines start with the hop number, and the last one names the BR if it was reached
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let last_hop = stdout.lines().rev().find_map(|l| {
+        let hop: u32 = l.split_whitespace().next()?.parse().ok()?;
+        Some((hop, l.contains(&br)))
+    });
+    match last_hop {
+        Some((hops, true)) => println!("BR {br} is {hops} hops away"),
+        Some((hops, false)) => println!("BR {br} wasn't reached within {hops} hops"),
+        None => println!("{traceroute} found no route to BR {br}"),
+    }
+    Ok(())
+}
+
+impl AddrArgs {
+    fn resolve(&self) -> anyhow::Result<std::net::Ipv6Addr> {
+        if let Some(addr) = self.seen {
+            return seen_addr(addr);
+        }
+        if let Some(dev) = &self.from_interface {
+            return interface_addr(dev);
+        }
+        if let Some(dev) = &self.from_ra {
+            return ra_addr(dev);
+        }
+        if self.auto {
+            return Ok(auto_addr(None)?.1);
+        }
+        match (&self.addr, &self.lease) {
+            (Some(addr), _) => match self.prefix() {
+                Some(prefix) => Ok(prefix.network()),
+                None => resolve_addr(addr),
+            },
+            (None, Some(lease)) => dhcp6::Lease::from_spec(lease)?.addr(),
+            (None, None) => detect_addr(),
+        }
+    }
+
+    fn calculate(&self) -> anyhow::Result<MapEData> {
+        match &self.lease {
+            Some(lease) => from_lease(&dhcp6::Lease::from_spec(lease)?),
+            None => {
+                let data = MapEData::from_addr(self.resolve()?)?;
+                // from_addr found a rule, so this does too
+                self.check_prefix(&rules::lookup(data.addr).unwrap().bmr)?;
+                Ok(data)
+            }
+        }
+    }
+
+    fn prefix(&self) -> Option<ipnet::Ipv6Net> {
+        self.addr.as_deref()?.parse().ok()
+    }
+
+    // A prefix given instead of an address has to be long enough to fix all of the rule's EA
+    // bits, or it's several customers' worth of mappings
+    fn check_prefix(&self, bmr: &v6plus_tun::Bmr) -> anyhow::Result<()> {
+        let Some(prefix) = self.prefix() else {
+            return Ok(());
         };
-        Ok(data)
+        let needed = bmr.ipv6_len + bmr.ea_len;
+        if prefix.prefix_len() < needed {
+            bail!(
+                "{prefix} is too short for the rule: its EA bits run to /{needed}, so give the /{needed} (or a longer prefix, or an address) instead"
+            );
+        }
+        Ok(())
+    }
+}
+
+// Everything that shapes the NAT rules, shared by setup-linux and the exporters so they can't
+// disagree
+#[derive(clap::Args)]
+pub struct NatArgs {
+    #[arg(
+        long,
+        help = "Redirect LAN IPv4 DNS to this resolver, such as a local resolver with IPv6 upstreams"
+    )]
+    pub redirect_dns: Option<std::net::Ipv4Addr>,
+    #[arg(
+        long = "no-snat-port",
+        value_parser = parse_port_range,
+        help = "Port or range (e.g. '5060' or '7920-7923') to keep out of SNAT, e.g. for port forwards"
+    )]
+    pub no_snat_ports: Vec<(u16, u16)>,
+    #[arg(
+        long = "pin",
+        value_parser = parse_pin,
+        help = "Give flows matching an iptables match their own ranges, as MATCH=RANGES, e.g. '-p udp --dport 3478:3481=5' or '-p tcp --dport 443=1-4'. Ranges are numbered as in calculate --list-ports"
+    )]
+    pub pins: Vec<Pin>,
+    #[arg(
+        long,
+        help = "Don't mark the router's own IPv4 traffic in OUTPUT, for setups marking it themselves. Unmarked, it isn't SNAT'd when there are several port ranges"
+    )]
+    pub no_local_marks: bool,
+}
+
+impl NatArgs {
+    pub fn plan(&self, port_ranges: &[(u16, u16)]) -> anyhow::Result<SnatPlan> {
+        // Ports are kept as they are with a fixed address, there's nothing to steer
+        if port_ranges == [(0, u16::MAX)]
+            && !(self.no_snat_ports.is_empty() && self.pins.is_empty())
+        {
+            bail!("every port is ours, so --no-snat-port and --pin have nothing to do");
+        }
+        SnatPlan::new(port_ranges, &self.no_snat_ports, &self.pins)
+    }
+
+    // The rules setup-linux installs, in the order they appear in their chains
+    pub fn rules(&self, plan: &SnatPlan, ipv4_addr: std::net::Ipv4Addr) -> Vec<FwRule> {
+        plan.rules(ipv4_addr, self.redirect_dns, !self.no_local_marks)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn interface_addr(dev: &str) -> anyhow::Result<std::net::Ipv6Addr> {
+    iface::select_wan_addr(&iface::resolve_dev(dev)?, |a| {
+        MapEData::from_addr(a).is_ok()
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_addr(_dev: &str) -> anyhow::Result<std::net::Ipv6Addr> {
+    bail!("--from-interface only works on Linux, pass the address instead");
+}
+
+// The start of the advertised prefix a rule covers, or failing that the first one, for the error
+#[cfg(target_os = "linux")]
+fn ra_addr(dev: &str) -> anyhow::Result<std::net::Ipv6Addr> {
+    let prefixes = ndisc::solicit(
+        &iface::resolve_dev(dev)?,
+        std::time::Duration::from_secs(10),
+    )?;
+    Ok(prefixes
+        .iter()
+        .map(|p| p.network())
+        .find(|a| rules::lookup(*a).is_some())
+        .unwrap_or(prefixes[0].network()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ra_addr(_dev: &str) -> anyhow::Result<std::net::Ipv6Addr> {
+    bail!("--from-ra only works on Linux, pass the address instead");
+}
+
+// Find the WAN and an address on it without being told anything: the WAN is wherever the IPv6
+// default route goes, and the prefix comes from an RA, then a DHCPv6 client's lease, then the
+// WAN's own addresses, whichever first gives one a rule covers
+#[cfg(target_os = "linux")]
+fn auto_addr(wan_dev: Option<&str>) -> anyhow::Result<(String, std::net::Ipv6Addr)> {
+    let wan_dev = match wan_dev {
+        Some(dev) => iface::resolve_dev(dev)?,
+        None => {
+            let dev = iface::default_route_dev()?;
+            eprintln!("Using {dev} as the WAN, it has the IPv6 default route");
+            dev
+        }
+    };
+    let known = |a: std::net::Ipv6Addr| MapEData::from_addr(a).is_ok();
+    let mut tried = vec![];
+    match ndisc::solicit(&wan_dev, std::time::Duration::from_secs(5)) {
+        Ok(prefixes) => match prefixes.iter().map(|p| p.network()).find(|a| known(*a)) {
+            Some(addr) => {
+                eprintln!("Using {addr} from a router advertisement");
+                return Ok((wan_dev, addr));
+            }
+            None => tried.push("router advertisements have no known prefix".to_string()),
+        },
+        Err(e) => tried.push(format!("{e:#}")),
+    }
+    for client in ["dhcpcd", "odhcp6c"] {
+        match dhcp6::Lease::from_spec(client).and_then(|l| l.addr()) {
+            Ok(addr) if known(addr) => {
+                eprintln!("Using {addr} from {client}'s delegated prefix");
+                return Ok((wan_dev, addr));
+            }
+            Ok(addr) => tried.push(format!("{client}'s prefix {addr} is not a known one")),
+            Err(e) => tried.push(format!("{e:#}")),
+        }
+    }
+    match iface::select_wan_addr(&wan_dev, known) {
+        Ok(addr) => Ok((wan_dev, addr)),
+        Err(e) => {
+            tried.push(format!("{e:#}"));
+            bail!(
+                "could not find an address to use:\n  {}",
+                tried.join("\n  ")
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn auto_addr(_wan_dev: Option<&str>) -> anyhow::Result<(String, std::net::Ipv6Addr)> {
+    bail!("--auto only works on Linux, pass the address instead");
+}
+
+// Ask the outside world what our IPv6 address is. This works without looking at any local
+// interfaces, so it's usable from a machine that isn't the router, as long as it's on the same
+// line.
+fn detect_addr() -> anyhow::Result<std::net::Ipv6Addr> {
+    // Google's authoritative servers answer this TXT query with the address the query came from.
+    let dig = [
+        "-6",
+        "+short",
+        "TXT",
+        "o-o.myaddr.l.google.com",
+        "@ns1.google.com",
+    ];
+    if let Ok(out) = command_output("dig", &dig) {
+        if let Ok(addr) = out.trim().trim_matches('"').parse() {
+            return Ok(addr);
+        }
+    }
+    // Fall back to an HTTPS echo service for systems without dig
+    let url = "https://api6.ipify.org";
+    let out = command_output("curl", &["-6", "-fsS", url])
+        .context("could not detect IPv6 address via DNS or HTTPS")?;
+    out.trim()
+        .parse()
+        .with_context(|| format!("unexpected response from {url}: {out}"))
+}
+
+// The same for IPv4, going out whichever way IPv4 goes now. Nothing answering is the normal case
+// on a MAP-E line before setting up.
+#[cfg(target_os = "linux")]
+fn detect_ipv4() -> anyhow::Result<std::net::Ipv4Addr> {
+    let dig = [
+        "-4",
+        "+short",
+        "+time=2",
+        "+tries=1",
+        "TXT",
+        "o-o.myaddr.l.google.com",
+        "@ns1.google.com",
+    ];
+    if let Ok(out) = command_output("dig", &dig) {
+        if let Ok(addr) = out.trim().trim_matches('"').parse() {
+            return Ok(addr);
+        }
+    }
+    let url = "https://api.ipify.org";
+    let out = command_output("curl", &["-4", "-fsS", "-m", "5", url])
+        .context("could not detect IPv4 address via DNS or HTTPS")?;
+    out.trim()
+        .parse()
+        .with_context(|| format!("unexpected response from {url}: {out}"))
+}
+
+// Like cmd_lib's run_fun!, but plain std so it works on every OS
+fn command_output(cmd: &str, args: &[&str]) -> anyhow::Result<String> {
+    let out = std::process::Command::new(cmd)
+        .args(args)
+        .output()
+        .with_context(|| format!("could not run {cmd}"))?;
+    if !out.status.success() {
+        bail!("{cmd} exited with {}", out.status);
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+// Check sites show whichever address the browser's request came from, which behind an HGW can be
+// the HGW's own. Only the end-user prefix goes into the calculation, so that's fine as long as it's
+// the same one this machine is in. Addresses that can't be a line's at all get told apart first.
+fn seen_addr(addr: std::net::Ipv6Addr) -> anyhow::Result<std::net::Ipv6Addr> {
+    let first = addr.segments()[0];
+    if addr.is_unspecified() || addr.is_loopback() || addr.is_multicast() {
+        bail!("{addr} isn't an address a check site could have seen");
+    }
+    if addr.to_ipv4_mapped().is_some() {
+        bail!(
+            "{addr} is an IPv4 address, so the check site was reached over IPv4 and saw no IPv6 address. Either the line has no IPv6 yet (check the HGW's IPv6 settings), or the browser preferred IPv4: check again from a host with IPv6"
+        );
+    }
+    if first & 0xffc0 == 0xfe80 {
+        bail!(
+            "{addr} is link-local, which only means something on one link. Copy the global address the check site reports instead"
+        );
+    }
+    if first & 0xfe00 == 0xfc00 {
+        bail!(
+            "{addr} is a unique local address, which never leaves the LAN, so no check site saw it. Copy the global address the check site reports instead"
+        );
+    }
+    if first == 0x2002 || (first == 0x2001 && addr.segments()[1] == 0) {
+        bail!(
+            "{addr} is a 6to4 or Teredo address, tunnelled over IPv4 rather than the line's own. Turn the tunnel off and check again"
+        );
+    }
+    let data = MapEData::from_addr(addr).with_context(|| {
+        format!(
+            "{addr} can't be calculated from: the line may use another IPv4 over IPv6 method (such as DS-Lite), or the ISP's rule isn't known (see --rules and --force)"
+        )
+    })?;
+    // from_addr found a rule, so this does too
+    let bmr = rules::lookup(addr).unwrap().bmr;
+    let len = bmr.ipv6_len + bmr.ea_len;
+    let end_user = ipnet::Ipv6Net::new(addr, len).unwrap().trunc();
+    eprintln!(
+        "Only {addr}'s first /{len} ({end_user}) goes into the calculation, so the HGW's own address gives the same parameters as a LAN host's behind it"
+    );
+    if data.edge_addr == addr {
+        eprintln!(
+            "Warning: {addr} is the CE address itself, so whatever it came from (most likely the HGW) is terminating MAP-E already. Turn its IPv4 over IPv6 off before setting up, or the two will fight over the IPv4 address and ports"
+        );
+    }
+    let locals = local_addrs();
+    if let Some(local) = locals.iter().find(|a| end_user.contains(*a)) {
+        eprintln!("This machine's {local} is in {end_user} too");
+    } else if let Some(local) = locals.iter().find(|a| rules::lookup(**a).is_some()) {
+        bail!(
+            "{addr} isn't in the same /{len} as this machine's {local}, so the check site was opened from another line (or through a VPN). Calculating from it would give someone else's IPv4 address and ports: use --from-interface, or this machine's own address"
+        );
+    }
+    Ok(addr)
+}
+
+#[cfg(target_os = "linux")]
+fn local_addrs() -> Vec<std::net::Ipv6Addr> {
+    iface::all_global_addrs()
+        .map(|addrs| addrs.into_iter().map(|a| a.addr).collect())
+        .unwrap_or_default()
+}
+
+// Only Linux gets compared against this machine's addresses
+#[cfg(not(target_os = "linux"))]
+fn local_addrs() -> Vec<std::net::Ipv6Addr> {
+    vec![]
+}
+
+// Accept either a literal address or a hostname, such as a DDNS name for a remote site. For
+// hostnames, the first AAAA record is used.
+fn resolve_addr(host: &str) -> anyhow::Result<std::net::Ipv6Addr> {
+    if let Ok(addr) = host.parse() {
+        return Ok(addr);
+    }
+    let addrs = (host, 0)
+        .to_socket_addrs()
+        .with_context(|| format!("could not resolve '{host}'"))?;
+    for addr in addrs {
+        if let std::net::SocketAddr::V6(v6) = addr {
+            return Ok(*v6.ip());
+        }
+    }
+    bail!("'{host}' has no AAAA record");
+}
+
+// The ISP's own MAP-E options win over our table, when it sends them
+fn from_lease(lease: &dhcp6::Lease) -> anyhow::Result<MapEData> {
+    let mut data = match lease.rule()? {
+        Some(rule) => MapEData::from_rule(lease.addr()?, &rule)?,
+        None => MapEData::from_addr(lease.addr()?)?,
+    };
+    data.fmrs = lease.fmrs.iter().map(Fmr::from).collect();
+    Ok(data)
+}
+
+// Ports in the dynamic range that are worth knowing about when deciding what to host on an owned
+// port. The flag marks ones that consumer routers, antivirus suites or ISPs commonly block.
+const NOTABLE_PORTS: &[(u16, u16, &str, bool)] = &[
+    (1194, 1194, "OpenVPN", false),
+    (1433, 1434, "MS SQL", true),
+    (1723, 1723, "PPTP", true),
+    (1900, 1900, "SSDP/UPnP", true),
+    (3074, 3074, "Xbox Live", false),
+    (3306, 3306, "MySQL", true),
+    (3389, 3389, "RDP", true),
+    (3478, 3479, "STUN/TURN", false),
+    (4500, 4500, "IPsec NAT-T", false),
+    (5060, 5061, "SIP", true),
+    (5353, 5353, "mDNS", true),
+    (5900, 5903, "VNC", true),
+    (6881, 6889, "BitTorrent", true),
+    (8080, 8080, "HTTP alternate", false),
+    (8443, 8443, "HTTPS alternate", false),
+    (25565, 25565, "Minecraft", false),
+    (27015, 27015, "Steam/Source", false),
+    (32400, 32400, "Plex", false),
+    (51820, 51820, "WireGuard", false),
+];
+
+fn print_ports(data: &MapEData) {
+    let ports: Vec<u16> = PortSet::new(data.port_ranges.iter().copied())
+        .iter()
+        .collect();
+    println!();
+    println!(
+        "Owned ports ({}), by range as numbered for --pin:",
+        ports.len()
+    );
+    for (i, &(start, end)) in data.port_ranges.iter().enumerate() {
+        let range: Vec<u16> = (start..=end).collect();
+        for (j, chunk) in range.chunks(16).enumerate() {
+            let line = chunk.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+            let index = if j == 0 {
+                format!("{}:", i + 1)
+            } else {
+                String::new()
+            };
+            println!("  {index:>3} {}", line.join(" "));
+        }
+    }
+    let notable: Vec<_> = ports
+        .iter()
+        .filter_map(|p| {
+            NOTABLE_PORTS
+                .iter()
+                .find(|(start, end, _, _)| (start..=end).contains(&p))
+                .map(|n| (p, n))
+        })
+        .collect();
+    if !notable.is_empty() {
+        println!("Notable ports:");
+        for (port, (_, _, name, blocked)) in notable {
+            let note = if *blocked { " (commonly blocked)" } else { "" };
+            println!("  {port}: {name}{note}");
+        }
     }
 }
 
 #[derive(Parser)]
-struct SetupLinux {
-    #[arg(required = true)]
-    addr: std::net::Ipv6Addr,
+struct CheckHgw {
+    #[command(flatten)]
+    input: AddrArgs,
     #[arg(
-        long = "wan",
+        long,
         required = true,
-        help = "WAN interface device, such as 'enp0s1' or 'eth0'"
-    )]
-    wan_dev: String,
-    #[arg(
-        long = "tun",
-        default_value = "ip4tun0",
-        help = "Tunnel interface to create, such as 'iptun0'"
-    )]
-    tun_dev: String,
-}
-
-impl SetupLinux {
-    fn setup(&self) -> anyhow::Result<()> {
-        let data = Calculate { addr: self.addr }.calculate()?;
-        let (tun_dev, br_addr, edge_addr, wan_dev) =
-            (&self.tun_dev, data.br_addr, data.edge_addr, &self.wan_dev);
-
-        // This is a copy of a well-known bash script that floats around the internet for people
-        // doing this sorta thing.
-        // Copyright unclear, I'll rewrite this in proper rust eventually, but for now I just want
-        // something that works.
-
-        // Add our side of the tunnel to the WAN interface, that's the CE addr
-        run_cmd!(ip -6 addr add $edge_addr dev $wan_dev)?;
-        // Add the tunnel
-        run_cmd!(ip -6 tunnel add $tun_dev mode ip4ip6 remote $br_addr local $edge_addr dev $wan_dev encaplimit none)?;
-        // TODO: calc mtu from WAN, not from hard coding it
-        run_cmd!(ip link set dev $tun_dev mtu 1460)?;
-        run_cmd!(ip link set dev $tun_dev up)?;
-
-        // all ipv4 goes over the tunnel
-        run_cmd!(ip route del default)?;
-        run_cmd!(ip route add default dev $tun_dev)?;
-
-        // and now nat rules
-        // Major TODO, we should not be flushing nat, we should be creating a chain and jumping to
-        // it and playing nice with other iptables users.
-        run_cmd!(iptables -t nat -F)?;
-        let num_ranges = data.port_ranges.len(); // always 15
-        let ipv4_addr = data.ipv4_addr;
-
-        // randomly snat to one of 15 port ranges externally based on our internally chosen sport.
-        // This gives us consistent routing, and also a reasonably even distribution.
-        let mark_base = 0x10;
-        run_cmd!(iptables -t mangle -I PREROUTING -j HMARK --hmark-tuple sport --hmark-mod $num_ranges --hmark-offset $mark_base --hmark-rnd 4)?;
-        for (i, (start, end)) in data.port_ranges.iter().enumerate() {
-            let mark = mark_base + i; // arbitrary
-            for proto in ["icmp", "tcp", "udp"] {
-                run_cmd!(iptables -t nat -A POSTROUTING -p $proto -o $tun_dev -m mark --mark $mark -j SNAT --to $ipv4_addr:$start-$end)?;
+        help = "Saved copy of the HGW's MAP-E status page (HTML or text), or '-' for stdin"
+    )]
+    page: String,
+}
+
+impl CheckHgw {
+    // HGW status pages vary wildly between models and firmware versions, so rather than trying to
+    // understand their layout, pull out everything that looks like an address or a number and
+    // check that our values show up.
+    fn check(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let page = if self.page == "-" {
+            std::io::read_to_string(std::io::stdin())?
+        } else {
+            std::fs::read_to_string(&self.page)
+                .with_context(|| format!("could not read {}", self.page))?
+        };
+
+        let tokens: Vec<&str> = page
+            .split(|c: char| !(c.is_ascii_hexdigit() || c == ':' || c == '.'))
+            .map(|t| t.trim_matches('.'))
+            .filter(|t| !t.is_empty())
+            .collect();
+        let v4: Vec<std::net::Ipv4Addr> = tokens.iter().filter_map(|t| t.parse().ok()).collect();
+        let v6: Vec<std::net::Ipv6Addr> = tokens.iter().filter_map(|t| t.parse().ok()).collect();
+        let numbers: std::collections::HashSet<u16> = page
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|t| t.parse().ok())
+            .collect();
+
+        let mut ok = true;
+        ok &= report("IPv4 address", &data.ipv4_addr, &v4);
+        ok &= report("CE IPv6 address", &data.edge_addr, &v6);
+        ok &= report("BR address", &data.br_addr, &v6);
+
+        let found = data
+            .port_ranges
+            .iter()
+            .filter(|(start, end)| numbers.contains(start) && numbers.contains(end))
+            .count();
+        println!("Port ranges: {found}/{} found", data.port_ranges.len());
+        ok &= found == data.port_ranges.len();
+
+        if !ok {
+            bail!("HGW page does not match the calculated parameters");
+        }
+        Ok(())
+    }
+}
+
+fn report<T: PartialEq + std::fmt::Display>(what: &str, ours: &T, seen: &[T]) -> bool {
+    if seen.contains(ours) {
+        println!("{what} {ours}: found");
+        return true;
+    }
+    if seen.is_empty() {
+        println!("{what} {ours}: not found");
+    } else {
+        let seen = seen.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        println!("{what} {ours}: not found, page shows {}", seen.join(", "));
+    }
+    false
+}
+
+#[derive(Parser)]
+struct Reverse {
+    #[arg(help = "Shared IPv4 address and port, such as '106.72.171.205:7920'")]
+    target: std::net::SocketAddrV4,
+}
+
+impl Reverse {
+    // Every rule whose IPv4 prefix holds the address, as overlapping rules (which `rules check`
+    // complains about) would each claim it
+    fn run(&self) -> anyhow::Result<()> {
+        let (ipv4, port) = (*self.target.ip(), self.target.port());
+        let (mut in_prefix, mut found) = (false, false);
+        for rule in rules::all().filter(|r| r.ipv4_prefix().contains(&ipv4)) {
+            in_prefix = true;
+            println!("Rule: {rule}");
+            let bmr = &rule.bmr;
+            let Some(psid) = v6plus_tun::psid_for_port(port, bmr.psid_offset, bmr.psid_len())
+            else {
+                println!(
+                    "  Port {port} is below {}, which no PSID owns",
+                    1u32 << (16 - bmr.psid_offset)
+                );
+                continue;
+            };
+            let prefix = bmr
+                .end_user_prefix(ipv4, psid)
+                .context("rule does not map the address")?;
+            let data = MapEData::from_rule(prefix, rule)?;
+            let ports = PortSet::new(data.port_ranges.iter().copied());
+            let range = ports
+                .range_of(port)
+                .map(|i| ports.ranges()[i])
+                .context("port is not in the PSID's ranges")?;
+            found = true;
+            println!("  PSID: {psid}");
+            println!("  Port range: {}-{}", range.0, range.1);
+            println!("  End-user prefix: {prefix}/{}", bmr.ipv6_len + bmr.ea_len);
+            println!("  CE IPv6 Addr: {}", data.edge_addr);
+            println!("  BR: {}", data.br_addr);
+        }
+        if !in_prefix {
+            bail!("{ipv4} is not in any rule's IPv4 prefix");
+        }
+        if !found {
+            bail!("no PSID owns port {port}");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+struct Capacity {
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "Concurrent sessions a device holds to any one busy destination"
+    )]
+    sessions_per_device: u32,
+    #[arg(long, help = "Devices in the household, to check against the estimate")]
+    devices: Option<u32>,
+}
+
+impl Capacity {
+    fn estimate(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let ports: u32 = data
+            .port_ranges
+            .iter()
+            .map(|(start, end)| (end - start) as u32 + 1)
+            .sum();
+        // SNAT only has to keep the full 5-tuple unique, so the same port can be reused towards
+        // different destinations. The limit that bites is many sessions to the same server (a
+        // CDN, a game server, a busy API), which is what this estimates.
+        let supported = ports / self.sessions_per_device.max(1);
+        println!("Owned ports: {ports} per protocol");
+        println!(
+            "Assuming {} concurrent sessions per device to a single destination, that's roughly {supported} devices",
+            self.sessions_per_device
+        );
+        if let Some(devices) = self.devices {
+            if devices > supported {
+                println!(
+                    "Warning: {devices} devices likely exceeds this; expect failed connections to popular services at busy times"
+                );
+            } else {
+                println!("{devices} devices should fit");
             }
         }
-        run_cmd!(iptables -t mangle -o $tun_dev --insert FORWARD 1 -p tcp --tcp-flags SYN,RST SYN -m tcpmss --mss 1400:65495 -j TCPMSS --clamp-mss-to-pmtu)?;
         Ok(())
     }
 }
 
+#[derive(Parser)]
+struct ListPrefixes {
+    #[arg(
+        long,
+        short,
+        help = "Also show where each rule came from and how trusted it is"
+    )]
+    verbose: bool,
+}
+
+impl ListPrefixes {
+    fn list(&self) {
+        for rule in rules::all() {
+            if self.verbose {
+                println!(
+                    "{rule} (confidence: {}, source: {})",
+                    rule.confidence, rule.source
+                );
+            } else {
+                println!("{rule}");
+            }
+        }
+    }
+}
+
+#[derive(Parser)]
+struct Rules {
+    #[command(subcommand)]
+    sub: RulesCommand,
+}
+
+#[derive(Subcommand)]
+enum RulesCommand {
+    /// Print every mapping rule in effect, built-in and loaded
+    List {
+        #[arg(long, help = "Print JSON instead of a table")]
+        json: bool,
+    },
+    /// Check the rules in effect for conflicts and broken port math, e.g. after writing a rules
+    /// file
+    Check,
+}
+
+impl Rules {
+    fn run(&self) -> anyhow::Result<()> {
+        match &self.sub {
+            RulesCommand::List { json } => list_rules(*json),
+            RulesCommand::Check => check_rules(),
+        }
+    }
+}
+
+fn check_rules() -> anyhow::Result<()> {
+    let problems = rules::check();
+    if problems.is_empty() {
+        println!("{} rules, no problems found", rules::all().count());
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("{problem}");
+    }
+    bail!("{} problems found", problems.len());
+}
+
+#[derive(Parser)]
+struct Services {
+    #[arg(long, help = "Print JSON instead of text")]
+    json: bool,
+}
+
+impl Services {
+    // Built from the rules in effect, so a rules file with a new service's rules shows up too
+    fn list(&self) -> anyhow::Result<()> {
+        let mut services: Vec<(&str, Vec<&rules::Rule>)> = rules::SERVICES
+            .iter()
+            .map(|s| (s.name, vec![]))
+            .chain([("other MAP-E rules (from --rules)", vec![])])
+            .collect();
+        for rule in rules::all() {
+            let name = rule.service().map(|s| s.name);
+            let i = rules::SERVICES
+                .iter()
+                .position(|s| Some(s.name) == name)
+                .unwrap_or(rules::SERVICES.len());
+            services[i].1.push(rule);
+        }
+        services.retain(|(_, rules)| !rules.is_empty());
+
+        let mut out = vec![];
+        for (name, rules) in &services {
+            // The best we can say about a service is what we can say about its best rule
+            let status = match rules.iter().map(|r| r.confidence).max() {
+                Some(rules::Confidence::Confirmed) => "supported, confirmed on a real line",
+                Some(rules::Confidence::Reported) => {
+                    "supported, but not yet confirmed on a real line"
+                }
+                _ => "experimental, its rules are inferred",
+            };
+            let min_prefix_len = rules
+                .iter()
+                .map(|r| r.bmr.ipv6_len + r.bmr.ea_len)
+                .max()
+                .unwrap_or(0);
+            let mut br_addrs: Vec<_> = rules.iter().map(|r| r.br_addr).collect();
+            br_addrs.sort();
+            br_addrs.dedup();
+            let prefixes: Vec<_> = rules.iter().map(|r| r.prefix().to_string()).collect();
+            if self.json {
+                out.push(serde_json::json!({
+                    "name": name,
+                    "mode": "MAP-E",
+                    "status": status,
+                    "prefixes": prefixes,
+                    "min_prefix_len": min_prefix_len,
+                    "br_addrs": br_addrs,
+                }));
+                continue;
+            }
+            println!("{name}");
+            println!("  Mode: MAP-E");
+            println!("  Status: {status}");
+            println!(
+                "  Needs: an IPv6 address, or a prefix of /{min_prefix_len} or longer, in {}",
+                prefixes.join(", ")
+            );
+            let br_addrs: Vec<_> = br_addrs.iter().map(|b| b.to_string()).collect();
+            println!("  BR: {}", br_addrs.join(", "));
+        }
+        // Nothing here speaks DS-Lite (an AFTR does the NAT, not us)
+        if self.json {
+            out.push(serde_json::json!({
+                "name": "DS-Lite services",
+                "mode": "DS-Lite",
+                "status": "not supported",
+            }));
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        } else {
+            println!("DS-Lite services");
+            println!("  Mode: DS-Lite");
+            println!("  Status: not supported");
+        }
+        Ok(())
+    }
+}
+
+fn list_rules(json: bool) -> anyhow::Result<()> {
+    if json {
+        let rules: Vec<_> = rules::all()
+            .map(|r| {
+                serde_json::json!({
+                    "ipv6_prefix": r.prefix().to_string(),
+                    "ipv4_prefix": r.ipv4_prefix().to_string(),
+                    "ea_len": r.bmr.ea_len,
+                    "psid_offset": r.bmr.psid_offset,
+                    "psid_len": r.bmr.psid_len(),
+                    "br_addr": r.br_addr,
+                    "ce_layout": r.ce_layout.to_string(),
+                    "confidence": r.confidence.to_string(),
+                    "source": r.source,
+                })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&rules)?);
+        return Ok(());
+    }
+    let mut rows = vec![[
+        "IPv6 prefix",
+        "IPv4 prefix",
+        "EA bits",
+        "PSID offset",
+        "PSID bits",
+        "BR",
+        "Confidence",
+    ]
+    .map(String::from)];
+    for r in rules::all() {
+        rows.push([
+            r.prefix().to_string(),
+            r.ipv4_prefix().to_string(),
+            r.bmr.ea_len.to_string(),
+            r.bmr.psid_offset.to_string(),
+            r.bmr.psid_len().to_string(),
+            r.br_addr.to_string(),
+            r.confidence.to_string(),
+        ]);
+    }
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| rows.iter().map(|r| r[i].len()).max().unwrap())
+        .collect();
+    for row in rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{cell:w$}"))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+    Ok(())
+}
+
+#[derive(Parser)]
+struct ReportVerified {
+    #[command(flatten)]
+    input: AddrArgs,
+}
+
+impl ReportVerified {
+    // Nothing is sent from here, we only print a pre-filled issue link for the user to open (or
+    // not). Only the rule's IPv6 prefix, cut to a /32, goes in it, never the address itself or
+    // anything IPv4.
+    fn report(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let rule = rules::lookup(data.addr).context("no rule matched")?;
+        // A fixed rule's IPv4 side is the user's own address, and a rules file can be as specific
+        // as one line, so neither says anything about the ISP's published rules
+        if rule.fixed() {
+            bail!("fixed IPv4 rules are for one customer's address, so there's nothing to report");
+        }
+        if rules::is_loaded(rule) {
+            bail!("the rule comes from --rules, which may be specific to your line; report only what you're happy to share by hand");
+        }
+        let prefix = ipnet::Ipv6Net::new(rule.bmr.ipv6_prefix, rule.bmr.ipv6_len.min(32))
+            .unwrap()
+            .trunc();
+        let title = format!("Verified: {prefix}");
+        let body = format!(
+            "The rule for {prefix} (confidence: {}) worked on my line.\n\nv6plus-tun {}",
+            rule.confidence,
+            env!("CARGO_PKG_VERSION"),
+        );
+        println!("This report contains only the following, nothing is sent until you submit it:");
+        println!();
+        println!("  {title}");
+        println!("  {}", body.replace('\n', "\n  "));
+        println!();
+        println!(
+            "https://github.com/euank/v6plus-tun/issues/new?title={}&body={}",
+            url_encode(&title),
+            url_encode(&body)
+        );
+        Ok(())
+    }
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
 #[derive(Subcommand)]
 enum Subcommands {
     Calculate(Calculate),
-    SetupLinux(SetupLinux),
+    /// Calculate with a mapping rule given on the command line, for any MAP-E ISP
+    CalculateGeneric(CalculateGeneric),
+    #[cfg(target_os = "linux")]
+    SetupLinux(linux::SetupLinux),
+    /// Wait for the WAN to get an address in a known prefix, e.g. before setup-linux at boot
+    #[cfg(target_os = "linux")]
+    WaitForPrefix(linux::WaitForPrefix),
+    /// Check the line for common problems before setting up, and explain what they mean
+    #[cfg(target_os = "linux")]
+    Doctor(doctor::Doctor),
+    /// Compare what the classic bash script set up with what setup-linux would, changing nothing
+    #[cfg(target_os = "linux")]
+    AuditLegacy(audit::AuditLegacy),
+    /// Compare the calculation against the parameters shown by the HGW's own MAP-E status page
+    CheckHgw(CheckHgw),
+    /// Check which of the old router's port forwards survive MAP-E, and remap the rest
+    CheckForwards(forwards::CheckForwards),
+    /// Find which PSID and CE address own an IPv4 address and port
+    Reverse(Reverse),
+    /// Estimate how many devices the allocated ports can support
+    Capacity(Capacity),
+    /// List the IPv6 prefixes with known mapping rules
+    ListPrefixes(ListPrefixes),
+    /// List the services (ISPs' IPv4 over IPv6 offerings) we know of, and whether they work
+    Services(Services),
+    /// Inspect the mapping rules
+    Rules(Rules),
+    /// Print a link to report that the rule for your prefix works
+    ReportVerified(ReportVerified),
+    /// Run a fake BR locally to test setup-linux against
+    #[cfg(target_os = "linux")]
+    Lab(lab::Lab),
+    /// Send test flows through the live config and check how they were SNAT'd
+    #[cfg(target_os = "linux")]
+    GenTraffic(traffic::GenTraffic),
+    /// Watch new connections being SNAT'd live, to spot apps eating ports
+    #[cfg(target_os = "linux")]
+    NatTop(top::NatTop),
+    /// Show the statistics setup-linux keeps locally: setups, prefix changes and tunnel uptime
+    #[cfg(target_os = "linux")]
+    Stats(stats::ShowStats),
+    /// Print JSON Schemas for the JSON this reads and writes, for front-ends to validate against
+    Schema(schema::Schema),
+    /// Print what setup would install in other formats
+    Export(export::Export),
+    /// Anything else is run as `v6plus-tun-<name> [args...]` from PATH, with the calculation for
+    /// the first argument passed as JSON on stdin
+    #[command(external_subcommand)]
+    External(Vec<OsString>),
+}
+
+// git-style plugins, so niche exporters can live outside this crate and still reuse the
+// calculation: `v6plus-tun foo <addr> args...` runs `v6plus-tun-foo args...`.
+fn run_plugin(args: Vec<OsString>) -> anyhow::Result<()> {
+    let (name, rest) = args.split_first().context("missing subcommand")?;
+    let name = name.to_string_lossy();
+    let Some((addr, plugin_args)) = rest.split_first() else {
+        bail!("usage: v6plus-tun {name} <addr> [args...]");
+    };
+    let data = MapEData::from_addr(resolve_addr(&addr.to_string_lossy())?)?;
+
+    let bin = format!("v6plus-tun-{name}");
+    let mut child = match std::process::Command::new(&bin)
+        .args(plugin_args)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("unrecognized subcommand '{name}', and no {bin} found on PATH");
+        }
+        Err(e) => return Err(e).with_context(|| format!("could not run {bin}")),
+    };
+    // stdin is dropped (and so closed) as soon as the data is written
+    serde_json::to_writer(child.stdin.take().unwrap(), &data)?;
+    let status = child.wait()?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if let Some(path) = &cli.rules {
+        rules::load(path)?;
+    }
 
     match cli.sub {
-        Subcommands::Calculate(c) => {
-            let data = c.calculate()?;
-            println!("{data}");
+        Subcommands::Calculate(c) => c.run(),
+        Subcommands::CalculateGeneric(c) => c.run(),
+        #[cfg(target_os = "linux")]
+        Subcommands::SetupLinux(s) => s.setup(),
+        #[cfg(target_os = "linux")]
+        Subcommands::WaitForPrefix(w) => w.wait(),
+        #[cfg(target_os = "linux")]
+        Subcommands::Doctor(d) => d.run(),
+        #[cfg(target_os = "linux")]
+        Subcommands::AuditLegacy(a) => a.run(),
+        Subcommands::CheckHgw(c) => c.check(),
+        Subcommands::CheckForwards(c) => c.check(),
+        Subcommands::Reverse(r) => r.run(),
+        Subcommands::Capacity(c) => c.estimate(),
+        Subcommands::ListPrefixes(l) => {
+            l.list();
             Ok(())
         }
-        Subcommands::SetupLinux(s) => s.setup(),
+        Subcommands::Services(s) => s.list(),
+        Subcommands::Rules(r) => r.run(),
+        Subcommands::ReportVerified(r) => r.report(),
+        #[cfg(target_os = "linux")]
+        Subcommands::GenTraffic(g) => g.run(),
+        #[cfg(target_os = "linux")]
+        Subcommands::NatTop(t) => t.run(),
+        #[cfg(target_os = "linux")]
+        Subcommands::Stats(s) => s.run(),
+        Subcommands::Export(e) => e.export(),
+        #[cfg(target_os = "linux")]
+        Subcommands::Lab(l) => l.run(),
+        Subcommands::Schema(s) => s.run(),
+        Subcommands::External(args) => run_plugin(args),
     }
 }
@@ -0,0 +1,180 @@
+//! The full set of parameters calculated for one address, as the CLI prints them and plugins get
+//! them on stdin.
+
+use anyhow::bail;
+
+use crate::rules::{self, CeLayout, Rule};
+use crate::{Bmr, Mapping};
+
+/// Everything needed to set up MAP-E for one end user
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct MapEData {
+    pub addr: std::net::Ipv6Addr,
+    pub ipv4_addr: std::net::Ipv4Addr,
+    pub br_addr: std::net::Ipv6Addr,
+    /// The CE address, i.e. the tunnel's source
+    pub edge_addr: std::net::Ipv6Addr,
+    pub psid: u16,
+    pub port_ranges: Vec<(u16, u16)>,
+    /// The rule it was calculated with
+    pub rule_prefix: ipnet::Ipv6Net,
+    pub rule_ipv4_prefix: ipnet::Ipv4Net,
+    pub ea_len: u8,
+    pub psid_offset: u8,
+    pub psid_len: u8,
+    /// Users sharing the IPv4 address, one per PSID
+    pub share_ratio: u32,
+    /// In MAP terms, the Default Mapping Rule: the BR's address
+    pub dmr: ipnet::Ipv6Net,
+    /// Forwarding Mapping Rules. Only DHCPv6 says which rules forward, so without it there are
+    /// none.
+    pub fmrs: Vec<Fmr>,
+    /// Why the IPv4 address looks wrong, for warning about rather than output
+    #[serde(skip)]
+    pub ipv4_doubt: Option<String>,
+}
+
+/// A Forwarding Mapping Rule, for reaching other end users of the rule directly rather than
+/// through the BR
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Fmr {
+    pub prefix: ipnet::Ipv6Net,
+    pub ipv4_prefix: ipnet::Ipv4Net,
+    pub ea_len: u8,
+    pub psid_offset: u8,
+}
+
+impl From<&Bmr> for Fmr {
+    fn from(bmr: &Bmr) -> Fmr {
+        Fmr {
+            prefix: ipnet::Ipv6Net::new(bmr.ipv6_prefix, bmr.ipv6_len).unwrap(),
+            ipv4_prefix: ipnet::Ipv4Net::new(bmr.ipv4_prefix, bmr.ipv4_len).unwrap(),
+            ea_len: bmr.ea_len,
+            psid_offset: bmr.psid_offset,
+        }
+    }
+}
+
+impl std::fmt::Display for Fmr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} -> {} (EA bits {}, PSID offset {})",
+            self.prefix, self.ipv4_prefix, self.ea_len, self.psid_offset
+        )
+    }
+}
+
+impl std::fmt::Display for MapEData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "IPv6 Addr: {}", self.addr)?;
+        writeln!(f, "IPv4 Addr (CE IPv4 Address): {}", self.ipv4_addr)?;
+        writeln!(f, "CE IPv6 Addr: {}", self.edge_addr)?;
+        writeln!(
+            f,
+            "Port Ranges: {}",
+            self.port_ranges
+                .iter()
+                .map(|el| format!("{}-{}", el.0, el.1))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )?;
+        writeln!(f, "PSID: {}", self.psid)?;
+        writeln!(f, "Border Relay Address (BR Address): {}", self.br_addr)?;
+        writeln!(f, "Rule: {} -> {}", self.rule_prefix, self.rule_ipv4_prefix)?;
+        writeln!(f, "EA Bits: {}", self.ea_bits())?;
+        writeln!(
+            f,
+            "PSID Offset/Length: {}/{}",
+            self.psid_offset, self.psid_len
+        )?;
+        writeln!(f, "Share Ratio: 1:{}", self.share_ratio)?;
+        writeln!(f, "DMR: {}", self.dmr)?;
+        match self.fmrs.as_slice() {
+            [] => writeln!(f, "FMRs: none"),
+            fmrs => {
+                for fmr in fmrs {
+                    writeln!(f, "FMR: {fmr}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl MapEData {
+    /// Puts together what `bmr` mapped `addr` to, with the CE address (laid out however the rule
+    /// wants) and BR to go with it
+    pub fn new(
+        addr: std::net::Ipv6Addr,
+        bmr: &Bmr,
+        mapping: Mapping,
+        edge_addr: std::net::Ipv6Addr,
+        br_addr: std::net::Ipv6Addr,
+    ) -> MapEData {
+        MapEData {
+            addr,
+            ipv4_addr: mapping.ipv4,
+            br_addr,
+            edge_addr,
+            psid: mapping.psid,
+            port_ranges: mapping.port_ranges,
+            rule_prefix: ipnet::Ipv6Net::new(bmr.ipv6_prefix, bmr.ipv6_len).unwrap(),
+            rule_ipv4_prefix: ipnet::Ipv4Net::new(bmr.ipv4_prefix, bmr.ipv4_len).unwrap(),
+            ea_len: bmr.ea_len,
+            psid_offset: bmr.psid_offset,
+            psid_len: mapping.psid_len,
+            share_ratio: 1 << mapping.psid_len,
+            dmr: ipnet::Ipv6Net::new(br_addr, 128).unwrap(),
+            fmrs: vec![],
+            ipv4_doubt: rules::ipv4_doubt(
+                mapping.ipv4,
+                ipnet::Ipv4Net::new(bmr.ipv4_prefix, bmr.ipv4_len).unwrap(),
+            ),
+        }
+    }
+
+    /// Calculates with the rule in effect for `addr` (see [`rules::lookup`])
+    pub fn from_addr(addr: std::net::Ipv6Addr) -> anyhow::Result<MapEData> {
+        let v6_segs = addr.segments();
+        let rule = match rules::lookup(addr) {
+            Some(rule) => rule,
+            None => match rules::nearest(addr) {
+                Some(near) => bail!(
+                    "unknown prefix: {:x}:{:x} (the nearest known rule is {near}; maybe a typo, or a rule we don't know yet)",
+                    v6_segs[0],
+                    v6_segs[1]
+                ),
+                None => bail!("unknown prefix: {:x}:{:x}", v6_segs[0], v6_segs[1]),
+            },
+        };
+        MapEData::from_rule(addr, rule)
+    }
+
+    /// Calculates with a given rule, e.g. one from DHCPv6
+    pub fn from_rule(addr: std::net::Ipv6Addr, rule: &Rule) -> anyhow::Result<MapEData> {
+        let Some(mapping) = rule.bmr.apply(addr) else {
+            bail!("{addr} is outside the rule {rule}");
+        };
+        let ce = match rule.ce_layout {
+            CeLayout::Jpne => crate::ce_from_parts(addr, mapping.ipv4, mapping.psid as u8),
+            CeLayout::Rfc7597 => mapping.ce,
+        };
+
+        let mut data = MapEData::new(addr, &rule.bmr, mapping, ce, rule.br_addr);
+        data.ipv4_doubt = rule.ipv4_doubt(data.ipv4_addr);
+        Ok(data)
+    }
+
+    /// Where the EA bits are, and how they split into the IPv4 suffix and PSID
+    pub fn ea_bits(&self) -> String {
+        let start = self.rule_prefix.prefix_len();
+        let end = start + self.ea_len;
+        format!(
+            "{} (address bits {start}-{end}: {} of IPv4 suffix, then {} of PSID)",
+            self.ea_len,
+            self.ea_len - self.psid_len,
+            self.psid_len
+        )
+    }
+}
@@ -0,0 +1,522 @@
+//! How outgoing connections get spread over our port ranges. setup-linux installs this and the
+//! exporters describe it, so both work from the same plan.
+
+use std::fmt::Write;
+
+use crate::ports::PortSet;
+use anyhow::bail;
+
+/// First fwmark used to pick a port range. Arbitrary, the bucket's index is added to it.
+pub const MARK_BASE: usize = 0x10;
+
+/// About the most HMARK buckets a plan gives the ranges, each of which is three SNAT rules. Every
+/// range keeps at least one, so many small leftovers can go a little over.
+pub const MAX_BUCKETS: usize = 64;
+
+/// One rule of a plan, for rendering as iptables or nft
+pub enum FwRule {
+    Hmark {
+        /// iptables matches, for pins
+        matches: Option<Vec<String>>,
+        buckets: usize,
+        offset: usize,
+        /// For the router's own traffic going out of the tunnel, rather than forwarded traffic
+        local: bool,
+    },
+    Snat {
+        proto: &'static str,
+        mark: Option<usize>,
+        ipv4_addr: std::net::Ipv4Addr,
+        range: (u16, u16),
+    },
+    DnsRedirect {
+        proto: &'static str,
+        dns: std::net::Ipv4Addr,
+    },
+    /// Clamps SYNs advertising an MSS of `from` or more to the route's
+    ClampMss { from: u16 },
+}
+
+/// Enough for the 1460-byte tunnel setup-linux makes, whose 1420-byte MSS is over it
+pub const CLAMP_FROM: u16 = 1400;
+
+impl FwRule {
+    /// (table, chain, rule) for iptables
+    pub fn iptables(&self, tun_dev: &str) -> (&'static str, &'static str, Vec<String>) {
+        let (table, chain, rule) = match self {
+            FwRule::Hmark {
+                matches,
+                buckets,
+                offset,
+                local,
+            } => (
+                "mangle",
+                if *local { "OUTPUT" } else { "PREROUTING" },
+                format!(
+                    "{} {} -j HMARK --hmark-tuple sport --hmark-mod {buckets} --hmark-offset {offset} --hmark-rnd 4",
+                    if *local { format!("-o {tun_dev}") } else { String::new() },
+                    matches.as_deref().unwrap_or_default().join(" ")
+                ),
+            ),
+            FwRule::Snat {
+                proto,
+                mark,
+                ipv4_addr,
+                range: (start, end),
+            } => (
+                "nat",
+                "POSTROUTING",
+                format!(
+                    "-p {proto} -o {tun_dev} {} -j SNAT --to {}",
+                    mark.map(|m| format!("-m mark --mark {m}"))
+                        .unwrap_or_default(),
+                    snat_to(*ipv4_addr, (*start, *end))
+                ),
+            ),
+            FwRule::DnsRedirect { proto, dns } => (
+                "nat",
+                "PREROUTING",
+                format!("! -i {tun_dev} -p {proto} --dport 53 ! -d {dns} -j DNAT --to-destination {dns}"),
+            ),
+            FwRule::ClampMss { from } => (
+                "mangle",
+                "FORWARD",
+                format!("-o {tun_dev} -p tcp --tcp-flags SYN,RST SYN -m tcpmss --mss {from}:65495 -j TCPMSS --clamp-mss-to-pmtu"),
+            ),
+        };
+        (
+            table,
+            chain,
+            rule.split_whitespace().map(String::from).collect(),
+        )
+    }
+
+    /// (chain, rule) for nft, in the chains iptables_restore's tables map to
+    pub fn nft(&self, tun_dev: &str) -> anyhow::Result<(&'static str, String)> {
+        Ok(match self {
+            FwRule::Hmark {
+                matches: Some(matches),
+                ..
+            } => bail!(
+                "pin '{}' is an iptables match, which can't be translated to nft",
+                matches.join(" ")
+            ),
+            FwRule::Hmark {
+                matches: None,
+                buckets,
+                offset,
+                local: false,
+            } => (
+                "prerouting",
+                format!("meta mark set jhash th sport mod {buckets} seed 0x4 offset {offset}"),
+            ),
+            FwRule::Hmark {
+                matches: None,
+                buckets,
+                offset,
+                local: true,
+            } => (
+                "output",
+                format!("oifname \"{tun_dev}\" meta mark set jhash th sport mod {buckets} seed 0x4 offset {offset}"),
+            ),
+            FwRule::Snat {
+                proto,
+                mark,
+                ipv4_addr,
+                range: (start, end),
+            } => (
+                "postrouting",
+                format!(
+                    "oifname \"{tun_dev}\" meta l4proto {proto} {}snat to {}",
+                    mark.map(|m| format!("meta mark {m:#x} ")).unwrap_or_default(),
+                    snat_to(*ipv4_addr, (*start, *end))
+                ),
+            ),
+            FwRule::DnsRedirect { proto, dns } => (
+                "prerouting_nat",
+                format!("iifname != \"{tun_dev}\" {proto} dport 53 ip daddr != {dns} dnat to {dns}"),
+            ),
+            FwRule::ClampMss { from } => (
+                "forward",
+                format!("oifname \"{tun_dev}\" tcp flags & (syn|rst) == syn tcp option maxseg size {from}-65495 tcp option maxseg size set rt mtu"),
+            ),
+        })
+    }
+}
+
+/// setup-linux's own chain in mangle for one of the built-in ones, which jumps to it. A rerun
+/// flushes and refills it, rather than stacking another copy of each rule on the old ones.
+pub fn mangle_chain(chain: &str) -> String {
+    format!("v6plus-tun-{}", chain.to_lowercase())
+}
+
+/// The built-in mangle chains that get one of ours
+pub const MANGLE_CHAINS: [&str; 3] = ["PREROUTING", "OUTPUT", "FORWARD"];
+
+/// Runs iptables with the arguments after `iptables`
+pub trait Iptables {
+    /// For checks and commands that are expected to fail, such as `-C` for a missing rule
+    fn test(&mut self, args: &[String]) -> bool;
+    /// For changes, which have to succeed
+    fn run(&mut self, args: &[String]) -> anyhow::Result<()>;
+}
+
+/// Installs the rules the way setup-linux does, replacing whatever an earlier run installed
+pub fn install_iptables(
+    rules: &[FwRule],
+    tun_dev: &str,
+    iptables: &mut impl Iptables,
+) -> anyhow::Result<()> {
+    let args = |a: &[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    // Major TODO, we should not be flushing nat, we should be creating a chain and jumping to
+    // it and playing nice with other iptables users.
+    iptables.run(&args(&["-t", "nat", "-F"]))?;
+    // Everything else goes in chains of our own, jumped to from the top of the built-in ones,
+    // ahead of other tools' rules. Left over marks would overwrite the plan's own with ones no
+    // SNAT rule matches, so each is emptied even when there's nothing to put back.
+    for chain in MANGLE_CHAINS {
+        let ours = mangle_chain(chain);
+        if !iptables.test(&args(&["-t", "mangle", "-F", &ours])) {
+            iptables.run(&args(&["-t", "mangle", "-N", &ours]))?;
+        }
+        if !iptables.test(&args(&["-t", "mangle", "-C", chain, "-j", &ours])) {
+            iptables.run(&args(&["-t", "mangle", "-I", chain, "1", "-j", &ours]))?;
+        }
+    }
+    for rule in rules {
+        let (table, chain, rule) = rule.iptables(tun_dev);
+        let chain = if table == "nat" {
+            chain.to_string()
+        } else {
+            // Earlier versions inserted straight into the built-in chains, and -D removes one
+            // copy at a time
+            let check = [args(&["-t", table, "-C", chain]), rule.clone()].concat();
+            let delete = [args(&["-t", table, "-D", chain]), rule.clone()].concat();
+            while iptables.test(&check) {
+                iptables.run(&delete)?;
+            }
+            mangle_chain(chain)
+        };
+        iptables.run(&[args(&["-t", table, "-A", &chain]), rule].concat())?;
+    }
+    Ok(())
+}
+
+/// Where SNAT sends a flow, for iptables and nft alike. With every port ours (a fixed IPv4
+/// address), ports are left alone as far as possible rather than remapped.
+pub fn snat_to(ipv4_addr: std::net::Ipv4Addr, (start, end): (u16, u16)) -> String {
+    if (start, end) == (0, u16::MAX) {
+        ipv4_addr.to_string()
+    } else {
+        format!("{ipv4_addr}:{start}-{end}")
+    }
+}
+
+/// The rules as an iptables-restore file, laid out as setup-linux installs them, so doctor finds
+/// them. Loading it replaces the nat and mangle tables wholesale (setup-linux flushes nat too);
+/// with --noflush, a reload adds another copy of each jump to our chains.
+pub fn iptables_restore(rules: &[FwRule], tun_dev: &str) -> String {
+    let mut out = String::new();
+    for table in ["mangle", "nat"] {
+        writeln!(out, "*{table}").unwrap();
+        if table == "mangle" {
+            for chain in MANGLE_CHAINS {
+                writeln!(out, ":{} - [0:0]", mangle_chain(chain)).unwrap();
+            }
+            for chain in MANGLE_CHAINS {
+                writeln!(out, "-A {chain} -j {}", mangle_chain(chain)).unwrap();
+            }
+        }
+        for rule in rules {
+            let (t, chain, args) = rule.iptables(tun_dev);
+            if t == table {
+                let chain = if t == "mangle" {
+                    mangle_chain(chain)
+                } else {
+                    chain.to_string()
+                };
+                writeln!(out, "-A {chain} {}", args.join(" ")).unwrap();
+            }
+        }
+        writeln!(out, "COMMIT").unwrap();
+    }
+    out
+}
+
+/// The same rules as an nft -f file, in a table of their own
+pub fn nft(rules: &[FwRule], tun_dev: &str) -> anyhow::Result<String> {
+    let chains = [
+        ("prerouting", "filter hook prerouting priority mangle"),
+        // Like iptables' mangle OUTPUT, rerouting packets whose mark changed
+        ("output", "route hook output priority mangle"),
+        ("forward", "filter hook forward priority mangle"),
+        ("prerouting_nat", "nat hook prerouting priority dstnat"),
+        ("postrouting", "nat hook postrouting priority srcnat"),
+    ];
+    let rules = rules
+        .iter()
+        .map(|r| r.nft(tun_dev))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut out = String::new();
+    // Recreating the table makes reloading idempotent
+    writeln!(out, "table ip v6plus_tun\ndelete table ip v6plus_tun\n").unwrap();
+    writeln!(out, "table ip v6plus_tun {{").unwrap();
+    for (name, hook) in chains {
+        writeln!(out, "    chain {name} {{").unwrap();
+        writeln!(out, "        type {hook};").unwrap();
+        for (chain, rule) in &rules {
+            if *chain == name {
+                writeln!(out, "        {rule}").unwrap();
+            }
+        }
+        writeln!(out, "    }}").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+/// Traffic matching an iptables match expression gets its own ranges, which nothing else uses
+#[derive(Clone, Debug)]
+pub struct Pin {
+    pub matches: Vec<String>,
+    /// Indexes into the calculated port ranges
+    pub ranges: Vec<usize>,
+}
+
+/// Parses "MATCH=RANGES", e.g. "-p udp --dport 3478:3481=5" or "-p tcp --dport 443=1-4,7". Ranges
+/// are numbered from 1, as listed by `calculate --list-ports`.
+pub fn parse_pin(s: &str) -> Result<Pin, String> {
+    let (matches, ranges) = s
+        .rsplit_once('=')
+        .ok_or_else(|| "expected MATCH=RANGES".to_string())?;
+    let matches: Vec<String> = matches.split_whitespace().map(String::from).collect();
+    if matches.is_empty() {
+        return Err("empty match expression".to_string());
+    }
+    let mut pin = Pin {
+        matches,
+        ranges: vec![],
+    };
+    for part in ranges.split(',') {
+        let (first, last) = part.split_once('-').unwrap_or((part, part));
+        let parse = |r: &str| match r.parse::<usize>() {
+            Ok(0) => Err("ranges are numbered from 1".to_string()),
+            Ok(r) => Ok(r - 1),
+            Err(e) => Err(format!("bad range '{r}': {e}")),
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first > last {
+            return Err(format!("range {} is after {}", first + 1, last + 1));
+        }
+        pin.ranges.extend(first..=last);
+    }
+    Ok(pin)
+}
+
+/// A set of buckets HMARK hashes flows into. Marks within a class are consecutive, so one HMARK
+/// rule with an offset covers them.
+pub struct Class {
+    /// None is everything not pinned
+    pub matches: Option<Vec<String>>,
+    pub marks: Vec<(usize, (u16, u16))>,
+}
+
+/// Which marks SNAT to which ranges, and which flows get which marks
+pub struct SnatPlan {
+    /// The ranges to SNAT to, after removing excluded ports, with how many HMARK buckets (and so
+    /// marks) each one gets
+    pub ranges: Vec<((u16, u16), usize)>,
+    /// The default class first, then one per pin, in the order they have to be applied
+    pub classes: Vec<Class>,
+}
+
+impl SnatPlan {
+    pub fn new(
+        port_ranges: &[(u16, u16)],
+        excluded: &[(u16, u16)],
+        pins: &[Pin],
+    ) -> anyhow::Result<SnatPlan> {
+        // (index of the calculated range it came from, range)
+        let pieces: Vec<(usize, (u16, u16))> = port_ranges
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &range)| {
+                let mut left = PortSet::new([range]);
+                for &ex in excluded {
+                    left.exclude(ex);
+                }
+                left.ranges().iter().map(|&r| (i, r)).collect::<Vec<_>>()
+            })
+            .collect();
+        if pieces.is_empty() {
+            bail!("no ports left to SNAT to");
+        }
+
+        // HMARK spreads flows evenly over buckets, so give each range buckets in proportion to
+        // its size. Otherwise a 2-port leftover from an exclusion would get as many flows as a
+        // full 16-port range and run out straight away. Dividing by the gcd keeps the number of
+        // buckets (and rules) down; with no exclusions it's one bucket per range. A lone excluded
+        // port makes the gcd 1, so past MAX_BUCKETS sizes are rounded to a coarser unit instead,
+        // which keeps them roughly in proportion.
+        let size = |(start, end): (u16, u16)| (end - start) as usize + 1;
+        let total: usize = pieces.iter().map(|&(_, r)| size(r)).sum();
+        let g = pieces.iter().map(|&(_, r)| size(r)).reduce(gcd).unwrap();
+        let unit = if total / g <= MAX_BUCKETS {
+            g
+        } else {
+            total.div_ceil(MAX_BUCKETS)
+        };
+        let weight = |r| ((size(r) + unit / 2) / unit).max(1);
+
+        for pin in pins {
+            if let Some(r) = pin.ranges.iter().find(|&&r| r >= port_ranges.len()) {
+                bail!(
+                    "pin '{}' uses range {}, but there are only {}",
+                    pin.matches.join(" "),
+                    r + 1,
+                    port_ranges.len()
+                );
+            }
+        }
+        let pinned = |i: &usize| pins.iter().any(|p| p.ranges.contains(i));
+        let mut next_mark = MARK_BASE;
+        let mut class = |matches: Option<Vec<String>>, keep: &dyn Fn(&usize) -> bool| {
+            let marks: Vec<_> = pieces
+                .iter()
+                .filter(|(i, _)| keep(i))
+                .flat_map(|&(_, r)| std::iter::repeat_n(r, weight(r)))
+                .map(|r| {
+                    next_mark += 1;
+                    (next_mark - 1, r)
+                })
+                .collect();
+            Class { matches, marks }
+        };
+        let mut classes = vec![class(None, &|i| !pinned(i))];
+        for pin in pins {
+            classes.push(class(Some(pin.matches.clone()), &|i| {
+                pin.ranges.contains(i)
+            }));
+        }
+        if let Some(empty) = classes.iter().find(|c| c.marks.is_empty()) {
+            match &empty.matches {
+                None => bail!("every range is pinned, leaving none for other traffic"),
+                Some(m) => bail!("pin '{}' has no ports left after exclusions", m.join(" ")),
+            }
+        }
+
+        Ok(SnatPlan {
+            ranges: pieces.into_iter().map(|(_, r)| (r, weight(r))).collect(),
+            classes,
+        })
+    }
+
+    /// The rules setup-linux installs, in the order they appear in their chains. `redirect_dns`
+    /// sends the LAN's IPv4 DNS there, and `local_marks` marks the router's own traffic too.
+    pub fn rules(
+        &self,
+        ipv4_addr: std::net::Ipv4Addr,
+        redirect_dns: Option<std::net::Ipv4Addr>,
+        local_marks: bool,
+    ) -> Vec<FwRule> {
+        let mut rules = vec![];
+        let protos = ["icmp", "tcp", "udp"];
+        if self.ranges.len() == 1 {
+            // One contiguous range (e.g. a fixed IP) needs no hashing at all
+            let (range, _) = self.ranges[0];
+            for proto in protos {
+                rules.push(FwRule::Snat {
+                    proto,
+                    mark: None,
+                    ipv4_addr,
+                    range,
+                });
+            }
+        } else {
+            // randomly snat to one of the port ranges externally based on our internally chosen
+            // sport. This gives us consistent routing, and also a distribution proportional to
+            // each range's size (see SnatPlan).
+            // Pinned flows are re-marked by later rules into their own ranges, which the default
+            // hash leaves out; marking doesn't stop traversal, so the last match wins.
+            for class in &self.classes {
+                rules.push(FwRule::Hmark {
+                    matches: class.matches.clone(),
+                    buckets: class.marks.len(),
+                    offset: class.marks[0].0,
+                    local: false,
+                });
+            }
+            // The router's own traffic (apt, curl) never passes PREROUTING, and without a mark
+            // matches no SNAT rule, leaving with whatever port it picked. The same marks in
+            // OUTPUT fix that. Pins on the incoming interface can't apply to it.
+            if local_marks {
+                for class in &self.classes {
+                    let inbound = class.matches.as_ref().is_some_and(|m| {
+                        m.iter()
+                            .any(|t| ["-i", "--in-interface"].contains(&t.as_str()))
+                    });
+                    if !inbound {
+                        rules.push(FwRule::Hmark {
+                            matches: class.matches.clone(),
+                            buckets: class.marks.len(),
+                            offset: class.marks[0].0,
+                            local: true,
+                        });
+                    }
+                }
+            }
+            for (mark, range) in self.marks() {
+                for proto in protos {
+                    rules.push(FwRule::Snat {
+                        proto,
+                        mark: Some(mark),
+                        ipv4_addr,
+                        range,
+                    });
+                }
+            }
+        }
+        // DNS is lots of tiny short-lived flows, each of which holds one of our few ports for a
+        // while. Sending it to a resolver that talks IPv6 upstream keeps it out of the tunnel.
+        if let Some(dns) = redirect_dns {
+            for proto in ["tcp", "udp"] {
+                rules.push(FwRule::DnsRedirect { proto, dns });
+            }
+        }
+        rules.push(FwRule::ClampMss { from: CLAMP_FROM });
+        rules
+    }
+
+    pub fn buckets(&self) -> usize {
+        self.marks().len()
+    }
+
+    /// Every bucket's mark, with the range it SNATs to
+    pub fn marks(&self) -> Vec<(usize, (u16, u16))> {
+        self.classes
+            .iter()
+            .flat_map(|c| c.marks.iter().copied())
+            .collect()
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Parses "5060" or "5000-5010", for flags taking ports
+pub fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let start: u16 = start
+        .parse()
+        .map_err(|e| format!("bad port '{start}': {e}"))?;
+    let end: u16 = end.parse().map_err(|e| format!("bad port '{end}': {e}"))?;
+    if start > end {
+        return Err(format!("{start} is after {end}"));
+    }
+    Ok((start, end))
+}
@@ -0,0 +1,161 @@
+// Neighbor discovery on the WAN. Prefix discovery from router advertisements, for lines without
+// prefix delegation (NGN without ひかり電話 only hands out a /64 by RA): we ask for one with a
+// router solicitation rather than waiting for the next unsolicited RA, which can take minutes. And
+// announcing the CE address once it's added, so neighbors that still have it cached against
+// another MAC (the HGW it was moved off, a bridge) update straight away.
+
+use std::io::Read;
+use std::net::{Ipv6Addr, SocketAddrV6};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+// RFC 4861 section 10: RTR_SOLICITATION_INTERVAL, MAX_NEIGHBOR_ADVERTISEMENT and RETRANS_TIMER
+const SOLICIT_INTERVAL: Duration = Duration::from_secs(4);
+const MAX_NEIGHBOR_ADVERTISEMENT: usize = 3;
+const RETRANS_TIMER: Duration = Duration::from_secs(1);
+
+fn ifindex(dev: &str) -> anyhow::Result<u32> {
+    Ok(
+        std::fs::read_to_string(format!("/sys/class/net/{dev}/ifindex"))
+            .with_context(|| format!("no device {dev}"))?
+            .trim()
+            .parse()?,
+    )
+}
+
+// This needs root (or CAP_NET_RAW)
+fn icmpv6_socket(dev: &str) -> anyhow::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+        .context("could not open an ICMPv6 socket, this needs root")?;
+    socket.bind_device(Some(dev.as_bytes()))?;
+    // Neighbor discovery messages are only valid with a hop limit of 255, which proves they
+    // weren't routed
+    socket.set_multicast_hops_v6(255)?;
+    Ok(socket)
+}
+
+// Solicit an RA on dev and return the global prefixes it advertises
+pub fn solicit(dev: &str, timeout: Duration) -> anyhow::Result<Vec<ipnet::Ipv6Net>> {
+    let ifindex = ifindex(dev)?;
+    let socket = icmpv6_socket(dev)?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let all_routers = SockAddr::from(SocketAddrV6::new(
+        Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2),
+        0,
+        0,
+        ifindex,
+    ));
+    // Type 133, code 0, then the checksum (the kernel fills it in) and 4 reserved bytes
+    let rs = [133, 0, 0, 0, 0, 0, 0, 0];
+
+    let start = Instant::now();
+    let mut last_sent: Option<Instant> = None;
+    let mut buf = [0; 1500];
+    while start.elapsed() < timeout {
+        if last_sent.is_none_or(|t| t.elapsed() >= SOLICIT_INTERVAL) {
+            socket
+                .send_to(&rs, &all_routers)
+                .with_context(|| format!("could not send a router solicitation on {dev}"))?;
+            last_sent = Some(Instant::now());
+        }
+        let len = match (&socket).read(&mut buf) {
+            Ok(len) => len,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e).context("could not receive from the ICMPv6 socket"),
+        };
+        let prefixes = parse_ra(&buf[..len]);
+        if !prefixes.is_empty() {
+            return Ok(prefixes);
+        }
+    }
+    bail!("no router advertisement with a global prefix on {dev} within {timeout:?}");
+}
+
+// The global prefixes in an RA's prefix information options (RFC 4861 section 4.6.2). Anything
+// that isn't an RA comes back empty, as the socket sees all ICMPv6.
+fn parse_ra(msg: &[u8]) -> Vec<ipnet::Ipv6Net> {
+    let mut prefixes = vec![];
+    if msg.first() != Some(&134) || msg.len() < 16 {
+        return prefixes;
+    }
+    let mut opts = &msg[16..];
+    while opts.len() >= 2 {
+        let len = opts[1] as usize * 8;
+        if len == 0 || len > opts.len() {
+            break;
+        }
+        let opt = &opts[..len];
+        opts = &opts[len..];
+        if opt[0] != 3 || len != 32 {
+            continue;
+        }
+        let valid = u32::from_be_bytes([opt[4], opt[5], opt[6], opt[7]]);
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&opt[16..32]);
+        let addr = Ipv6Addr::from(octets);
+        // A zero lifetime withdraws the prefix. Link-local and ULA prefixes are never in a rule.
+        let segs = addr.segments();
+        if valid == 0 || segs[0] & 0xffc0 == 0xfe80 || segs[0] & 0xfe00 == 0xfc00 {
+            continue;
+        }
+        if let Ok(prefix) = ipnet::Ipv6Net::new(addr, opt[2]) {
+            prefixes.push(prefix.trunc());
+        }
+    }
+    prefixes
+}
+
+// Send unsolicited neighbor advertisements for addr, already configured on dev, to all nodes
+// (RFC 4861 section 7.2.6). The override flag makes neighbors replace whatever link-layer address
+// they had cached for it.
+pub fn announce(dev: &str, addr: Ipv6Addr) -> anyhow::Result<()> {
+    let ifindex = ifindex(dev)?;
+    let socket = icmpv6_socket(dev)?;
+    socket
+        .bind(&SocketAddrV6::new(addr, 0, 0, ifindex).into())
+        .with_context(|| format!("{addr} is not configured on {dev}"))?;
+    let all_nodes = SockAddr::from(SocketAddrV6::new(
+        Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1),
+        0,
+        0,
+        ifindex,
+    ));
+    // Type 136, code 0, the checksum (filled in by the kernel), then the flags: only override,
+    // as this isn't a router or a reply to a solicitation
+    let mut na = vec![136, 0, 0, 0, 0x20, 0, 0, 0];
+    na.extend_from_slice(&addr.octets());
+    // A target link-layer address option, if the device has one
+    if let Some(mac) = mac_addr(dev) {
+        na.extend_from_slice(&[2, 1]);
+        na.extend_from_slice(&mac);
+    }
+    for i in 0..MAX_NEIGHBOR_ADVERTISEMENT {
+        if i > 0 {
+            std::thread::sleep(RETRANS_TIMER);
+        }
+        socket
+            .send_to(&na, &all_nodes)
+            .with_context(|| format!("could not send a neighbor advertisement on {dev}"))?;
+    }
+    Ok(())
+}
+
+fn mac_addr(dev: &str) -> Option<[u8; 6]> {
+    let s = std::fs::read_to_string(format!("/sys/class/net/{dev}/address")).ok()?;
+    let octets = s
+        .trim()
+        .split(':')
+        .map(|o| u8::from_str_radix(o, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let mac: [u8; 6] = octets.try_into().ok()?;
+    (mac != [0; 6]).then_some(mac)
+}
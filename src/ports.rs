@@ -0,0 +1,133 @@
+//! Sets of ports, such as the ones a PSID owns, for working with them port by port rather than
+//! range by range.
+
+/// Ports as sorted, non-overlapping inclusive ranges.
+///
+/// Adjacent ranges aren't merged, so each of a PSID's ranges stays one range and keeps its index
+/// (as `calculate --list-ports` and pins number them).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortSet {
+    ranges: Vec<(u16, u16)>,
+}
+
+impl PortSet {
+    /// Overlapping ranges are merged, and ones ending before they start ignored.
+    pub fn new(ranges: impl IntoIterator<Item = (u16, u16)>) -> PortSet {
+        let mut sorted: Vec<_> = ranges.into_iter().filter(|(s, e)| s <= e).collect();
+        sorted.sort_unstable();
+        let mut merged: Vec<(u16, u16)> = vec![];
+        for (start, end) in sorted {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        PortSet { ranges: merged }
+    }
+
+    /// The ports `psid` owns, see [`port_ranges`](crate::port_ranges)
+    pub fn for_psid(psid: u16, a: u8, k: u8) -> PortSet {
+        PortSet {
+            ranges: crate::port_ranges(psid, a, k),
+        }
+    }
+
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.ranges
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.range_of(port).is_some()
+    }
+
+    /// Whether all of `start` to `end` is in the set, within one range
+    pub fn contains_range(&self, (start, end): (u16, u16)) -> bool {
+        self.range_of(start)
+            .is_some_and(|i| start <= end && end <= self.ranges[i].1)
+    }
+
+    /// The index of the range `port` is in
+    pub fn range_of(&self, port: u16) -> Option<usize> {
+        let i = self.ranges.partition_point(|&(_, end)| end < port);
+        (self.ranges.get(i)?.0 <= port).then_some(i)
+    }
+
+    /// The number of ports
+    pub fn len(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|&(start, end)| (end - start) as usize + 1)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Every port, in order
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.ranges.iter().flat_map(|&(start, end)| start..=end)
+    }
+
+    /// The `n`th port (from 0) in order, without going through the ones before it
+    pub fn nth_port(&self, mut n: usize) -> Option<u16> {
+        for &(start, end) in &self.ranges {
+            let len = (end - start) as usize + 1;
+            if n < len {
+                return Some(start + n as u16);
+            }
+            n -= len;
+        }
+        None
+    }
+
+    /// Where each of `wanted` (e.g. forwarded ports) can go: where it is, if that's in the set,
+    /// or else the first span of the same size within one range that none of the others take.
+    /// Ranges aren't adjacent, so a span can't straddle two. `None` for ones nothing is left for;
+    /// earlier ones get first pick.
+    pub fn remap(&self, wanted: &[(u16, u16)]) -> Vec<Option<(u16, u16)>> {
+        let mut taken: Vec<(u16, u16)> = wanted
+            .iter()
+            .copied()
+            .filter(|&p| self.contains_range(p))
+            .collect();
+        wanted
+            .iter()
+            .map(|&(start, end)| {
+                if self.contains_range((start, end)) {
+                    return Some((start, end));
+                }
+                let len = end.checked_sub(start)?;
+                let free = self.ranges.iter().find_map(|&(r_start, r_end)| {
+                    (r_start..=r_end.checked_sub(len)?)
+                        .map(|s| (s, s + len))
+                        .find(|&(s, e)| !taken.iter().any(|&(t_s, t_e)| s <= t_e && t_s <= e))
+                })?;
+                taken.push(free);
+                Some(free)
+            })
+            .collect()
+    }
+
+    /// Takes `start` to `end` out of the set, splitting any range they're in the middle of
+    pub fn exclude(&mut self, (start, end): (u16, u16)) {
+        self.ranges = self
+            .ranges
+            .iter()
+            .flat_map(|&(r_start, r_end)| {
+                if end < r_start || start > r_end {
+                    return vec![(r_start, r_end)];
+                }
+                // Whatever is left on either side of the excluded ports
+                let mut split = vec![];
+                if r_start < start {
+                    split.push((r_start, start - 1));
+                }
+                if r_end > end {
+                    split.push((end + 1, r_end));
+                }
+                split
+            })
+            .collect();
+    }
+}
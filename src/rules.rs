@@ -0,0 +1,500 @@
+//! Base mapping rules I think? Pulled from ~the internet~
+//! Each entry records where it came from and how much we trust it, since "someone's calculator
+//! said so" and "confirmed working on a real line" are very different things.
+
+use anyhow::{bail, Context};
+
+use crate::trie::PrefixTrie;
+
+/// Ordered from least to most trusted. Nothing in the built-in table is confirmed (yet)
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Confidence {
+    /// Derived from the structure of other entries, not seen in any published table
+    Inferred,
+    /// Published by a third party (another calculator, a blog post), but not confirmed by us
+    Reported,
+    /// Confirmed working on a real line
+    Confirmed,
+}
+
+impl std::fmt::Display for Confidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Confidence::Inferred => "inferred",
+            Confidence::Reported => "reported",
+            Confidence::Confirmed => "confirmed",
+        })
+    }
+}
+
+/// How a rule lays out the CE address' interface ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CeLayout {
+    /// The pre-RFC 7597 interface ID JPNE uses, see [`ce_from_parts`](crate::ce_from_parts). Only for /32 rules
+    /// onto IPv4 /16s with 8-bit PSIDs.
+    Jpne,
+    /// RFC 7597's own: 16 zero bits, the IPv4 address, then the PSID
+    Rfc7597,
+}
+
+impl std::fmt::Display for CeLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CeLayout::Jpne => "jpne",
+            CeLayout::Rfc7597 => "rfc7597",
+        })
+    }
+}
+
+/// A mapping rule with the BR to use it with, and where it came from
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Rule {
+    pub bmr: crate::Bmr,
+    pub br_addr: std::net::Ipv6Addr,
+    pub ce_layout: CeLayout,
+    pub source: &'static str,
+    pub confidence: Confidence,
+}
+
+impl Rule {
+    // A v6plus-style rule: a /32 onto an IPv4 /16, PSID offset 4 and 8 PSID bits
+    const fn jpne(
+        prefix: (u16, u16),
+        ipv4_prefix: (u8, u8),
+        br_addr: std::net::Ipv6Addr,
+        source: &'static str,
+        confidence: Confidence,
+    ) -> Rule {
+        Rule {
+            bmr: crate::Bmr {
+                ipv6_prefix: std::net::Ipv6Addr::new(prefix.0, prefix.1, 0, 0, 0, 0, 0, 0),
+                ipv6_len: 32,
+                ipv4_prefix: std::net::Ipv4Addr::new(ipv4_prefix.0, ipv4_prefix.1, 0, 0),
+                ipv4_len: 16,
+                ea_len: 24,
+                psid_offset: 4,
+            },
+            br_addr,
+            ce_layout: CeLayout::Jpne,
+            source,
+            confidence,
+        }
+    }
+
+    pub fn prefix(&self) -> ipnet::Ipv6Net {
+        ipnet::Ipv6Net::new(self.bmr.ipv6_prefix, self.bmr.ipv6_len).unwrap()
+    }
+
+    pub fn ipv4_prefix(&self) -> ipnet::Ipv4Net {
+        ipnet::Ipv4Net::new(self.bmr.ipv4_prefix, self.bmr.ipv4_len).unwrap()
+    }
+
+    /// A fixed IPv4 address service: one address with every port, so no PSID
+    pub fn fixed(&self) -> bool {
+        self.bmr.ipv4_len == 32 && self.bmr.psid_len() == 0
+    }
+
+    /// Why the IPv4 address calculated with this rule is doubtful, if it is. A JPNE-style rule
+    /// nobody has reported should land in one of the prefixes JPNE's built-in rules map onto. One
+    /// from a rules file is reported or confirmed, and may well be for an allocation we don't know.
+    pub fn ipv4_doubt(&self, ipv4: std::net::Ipv4Addr) -> Option<String> {
+        ipv4_doubt(ipv4, self.ipv4_prefix()).or_else(|| {
+            (self.ce_layout == CeLayout::Jpne
+                && self.confidence == Confidence::Inferred
+                && !RULES.iter().any(|r| r.ipv4_prefix().contains(&ipv4)))
+            .then(|| format!("{ipv4} isn't in any IPv4 prefix the built-in JPNE rules map onto"))
+        })
+    }
+
+    /// None for rules from a rules file with a BR of their own
+    pub fn service(&self) -> Option<&'static Service> {
+        SERVICES.iter().find(|s| s.br_addrs.contains(&self.br_addr))
+    }
+}
+
+const FC2: &str = "http://ipv4.web.fc2.com/map-e.html";
+// The published rules are /31s mapping onto IPv4 /15s, with IPv6 bit 32 becoming the low bit of
+// the second IPv4 octet. Where only one half of a /31 is listed, the other half follows from that.
+const OTHER_HALF: &str = "the other half of a published /31 rule";
+
+// BIGLOBE has a BR per /30 of rules, v6plus a single one
+const BIGLOBE_BR_1: std::net::Ipv6Addr =
+    std::net::Ipv6Addr::new(0x2001, 0x260, 0x700, 0x1, 0, 0, 0x1, 0x275);
+const BIGLOBE_BR_2: std::net::Ipv6Addr =
+    std::net::Ipv6Addr::new(0x2001, 0x260, 0x700, 0x1, 0, 0, 0x1, 0x276);
+const V6PLUS_BR: std::net::Ipv6Addr =
+    std::net::Ipv6Addr::new(0x2404, 0x9200, 0x225, 0x100, 0, 0, 0, 0x64);
+
+/// The MAP-E services the rules belong to, told apart by their BRs
+pub struct Service {
+    pub name: &'static str,
+    pub br_addrs: &'static [std::net::Ipv6Addr],
+}
+
+pub const SERVICES: &[Service] = &[
+    Service {
+        name: "v6plus",
+        br_addrs: &[V6PLUS_BR],
+    },
+    Service {
+        name: "BIGLOBE IPv6オプション",
+        br_addrs: &[BIGLOBE_BR_1, BIGLOBE_BR_2],
+    },
+];
+
+/// The built-in table, for lines without a rules file or DHCPv6 rules
+pub const RULES: &[Rule] = &[
+    // BIGLOBE's IPv6オプション, also a JPNE MAP-E service, but on BIGLOBE's own prefixes and BRs
+    Rule::jpne(
+        (0x2404, 0x7a80),
+        (133, 200),
+        BIGLOBE_BR_1,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x2404, 0x7a81),
+        (133, 201),
+        BIGLOBE_BR_1,
+        OTHER_HALF,
+        Confidence::Inferred,
+    ),
+    Rule::jpne(
+        (0x2404, 0x7a84),
+        (133, 206),
+        BIGLOBE_BR_2,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x2404, 0x7a85),
+        (133, 207),
+        BIGLOBE_BR_2,
+        OTHER_HALF,
+        Confidence::Inferred,
+    ),
+    // v6plus proper
+    Rule::jpne(
+        (0x240b, 0x10),
+        (106, 72),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x240b, 0x11),
+        (106, 73),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x240b, 0x12),
+        (14, 8),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x240b, 0x13),
+        (14, 9),
+        V6PLUS_BR,
+        OTHER_HALF,
+        Confidence::Inferred,
+    ),
+    Rule::jpne(
+        (0x240b, 0x250),
+        (14, 10),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x240b, 0x251),
+        (14, 11),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x240b, 0x252),
+        (14, 12),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+    Rule::jpne(
+        (0x240b, 0x253),
+        (14, 13),
+        V6PLUS_BR,
+        FC2,
+        Confidence::Reported,
+    ),
+];
+
+// Rules loaded with --rules, which come before (and so override) the built-in ones
+static LOADED: std::sync::OnceLock<Vec<Rule>> = std::sync::OnceLock::new();
+
+// A rule as written in a rules file, e.g. in TOML:
+//
+//     [[rule]]
+//     prefix = "2404:7a80::/32"
+//     ipv4_prefix = "133.200.0.0/16"
+//     br = "2001:260:700:1::1:275"      # optional if a built-in rule covers the prefix
+//     ea_len = 24                       # optional, defaults to v6plus' 8 PSID bits
+//     psid_offset = 4                   # optional, defaults to v6plus' 4
+//     ce_layout = "rfc7597"             # optional, defaults to "jpne"
+//     confidence = "confirmed"          # optional, defaults to "reported"
+//     source = "my own line"            # optional, defaults to the file name
+//
+// `fixed = true` makes it a fixed IPv4 address service instead: prefix is your own, ipv4_prefix a
+// /32, and there are no EA bits or PSID.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileRule {
+    prefix: ipnet::Ipv6Net,
+    ipv4_prefix: ipnet::Ipv4Net,
+    br: Option<std::net::Ipv6Addr>,
+    ea_len: Option<u8>,
+    psid_offset: Option<u8>,
+    ce_layout: Option<CeLayout>,
+    confidence: Option<Confidence>,
+    source: Option<String>,
+    fixed: Option<bool>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    rule: Vec<FileRule>,
+}
+
+/// Loads a rules file, TOML unless it's named *.json. Only call this once, before any lookups.
+pub fn load(path: &std::path::Path) -> anyhow::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let file: RulesFile = if path.extension().is_some_and(|e| e == "json") {
+        serde_json::from_str(&text).with_context(|| format!("{} is not valid", path.display()))?
+    } else {
+        toml::from_str(&text).with_context(|| format!("{} is not valid", path.display()))?
+    };
+    let default_source: &'static str = path.display().to_string().leak();
+    let builtin = trie(RULES.iter());
+    let mut rules = vec![];
+    for (i, r) in file.rule.into_iter().enumerate() {
+        let context = || format!("{}: rule {} ({})", path.display(), i + 1, r.prefix);
+        let fixed = r.fixed.unwrap_or(false);
+        if fixed
+            && (r.ipv4_prefix.prefix_len() != 32 || r.ea_len.is_some() || r.psid_offset.is_some())
+        {
+            return Err(anyhow::anyhow!(
+                "a fixed rule is for a single IPv4 address (a /32), with no ea_len or psid_offset"
+            ))
+            .with_context(context);
+        }
+        let bmr = crate::Bmr {
+            ipv6_prefix: r.prefix.network(),
+            ipv6_len: r.prefix.prefix_len(),
+            ipv4_prefix: r.ipv4_prefix.network(),
+            ipv4_len: r.ipv4_prefix.prefix_len(),
+            ea_len: match fixed {
+                true => 0,
+                false => r.ea_len.unwrap_or(32 - r.ipv4_prefix.prefix_len() + 8),
+            },
+            psid_offset: match fixed {
+                true => 0,
+                false => r.psid_offset.unwrap_or(4),
+            },
+        };
+        bmr.validate()
+            .map_err(anyhow::Error::msg)
+            .with_context(context)?;
+        // There's no CE address to lay out, the tunnel address comes from the ISP
+        let ce_layout = r.ce_layout.unwrap_or(match fixed {
+            true => CeLayout::Rfc7597,
+            false => CeLayout::Jpne,
+        });
+        if ce_layout == CeLayout::Jpne
+            && (bmr.ipv6_len, bmr.ipv4_len, bmr.psid_len()) != (32, 16, 8)
+        {
+            return Err(anyhow::anyhow!(
+                "the jpne CE layout only works for /32 rules onto IPv4 /16s with 8-bit PSIDs, use ce_layout = \"rfc7597\""
+            ))
+            .with_context(context);
+        }
+        // Without a BR of its own, a rule overriding (part of) a built-in one uses its BR
+        let br_addr = match r.br {
+            Some(br) => br,
+            None => match builtin.lookup(r.prefix.network()) {
+                Some((len, builtin)) if len <= bmr.ipv6_len => builtin.br_addr,
+                _ => return Err(anyhow::anyhow!("no br given")).with_context(context),
+            },
+        };
+        rules.push(Rule {
+            bmr,
+            br_addr,
+            ce_layout,
+            source: r.source.map_or(default_source, |s| s.leak()),
+            confidence: r.confidence.unwrap_or(Confidence::Reported),
+        });
+    }
+    if LOADED.set(rules).is_err() {
+        bail!("rules were already loaded");
+    }
+    Ok(())
+}
+
+/// Why `ipv4` is unlikely to be what an ISP's rule for `prefix` gives out, if it is. A wrong rule
+/// (or --ipv4-prefix) calculates an address just as happily as the right one, so this catches it
+/// before a dead tunnel does.
+pub fn ipv4_doubt(ipv4: std::net::Ipv4Addr, prefix: ipnet::Ipv4Net) -> Option<String> {
+    if !prefix.contains(&ipv4) {
+        return Some(format!("{ipv4} is outside the rule's IPv4 prefix {prefix}"));
+    }
+    let in_net = |net: &str| net.parse::<ipnet::Ipv4Net>().unwrap().contains(&ipv4);
+    let kind = if ipv4.is_private() {
+        "a private address"
+    } else if ipv4.is_loopback() {
+        "a loopback address"
+    } else if ipv4.is_link_local() {
+        "a link-local address"
+    } else if ipv4.is_documentation() {
+        "a documentation address"
+    } else if in_net("100.64.0.0/10") {
+        "a carrier-grade NAT address"
+    } else if ipv4.is_multicast() {
+        "a multicast address"
+    } else if in_net("0.0.0.0/8")
+        || in_net("192.0.0.0/24")
+        || in_net("198.18.0.0/15")
+        || in_net("240.0.0.0/4")
+    {
+        "a reserved address"
+    } else {
+        return None;
+    };
+    Some(format!("{ipv4} is {kind}, which no ISP hands out"))
+}
+
+/// Whether `rule` came from a rules file rather than the built-in table
+pub fn is_loaded(rule: &Rule) -> bool {
+    LOADED
+        .get()
+        .is_some_and(|l| l.iter().any(|r| std::ptr::eq(r, rule)))
+}
+
+/// Every rule in effect, loaded ones first
+pub fn all() -> impl Iterator<Item = &'static Rule> {
+    let loaded = LOADED.get().map(|r| r.as_slice()).unwrap_or_default();
+    loaded.iter().chain(
+        RULES
+            .iter()
+            .filter(|r| !loaded.iter().any(|l| l.prefix() == r.prefix())),
+    )
+}
+
+// Overlapping rules are fine, the most specific one covering an address wins. Of two rules for
+// the same prefix the first wins, which puts loaded rules ahead of built-in ones.
+fn trie<'a>(rules: impl Iterator<Item = &'a Rule>) -> PrefixTrie<&'a Rule> {
+    let mut trie = PrefixTrie::new();
+    for rule in rules {
+        trie.insert(rule.bmr.ipv6_prefix, rule.bmr.ipv6_len, rule);
+    }
+    trie
+}
+
+// Built on first use, so after any rules file is loaded
+static TRIE: std::sync::OnceLock<PrefixTrie<&'static Rule>> = std::sync::OnceLock::new();
+
+/// The most specific rule in effect covering `addr`
+pub fn lookup(addr: std::net::Ipv6Addr) -> Option<&'static Rule> {
+    TRIE.get_or_init(|| trie(all()))
+        .lookup(addr)
+        .map(|(_, rule)| *rule)
+}
+
+/// The rule closest to `addr`, for suggesting what's wrong when no rule matches
+pub fn nearest(addr: std::net::Ipv6Addr) -> Option<&'static Rule> {
+    TRIE.get_or_init(|| trie(all())).nearest(addr).copied()
+}
+
+/// Problems with the rules in effect, for `rules check`. Overrides are fine as long as they agree
+/// with what they override; anything else means someone somewhere gets the wrong mapping.
+pub fn check() -> Vec<String> {
+    let rules: Vec<_> = all().collect();
+    let mut problems = vec![];
+    for rule in &rules {
+        if let Err(e) = rule.bmr.validate() {
+            problems.push(format!("{rule}: {e}"));
+            continue;
+        }
+        let br = rule.br_addr;
+        if br.is_unspecified() || br.is_loopback() || br.is_multicast() {
+            problems.push(format!("{rule}: the BR {br} is not a unicast address"));
+        } else if rule.prefix().contains(&br) {
+            problems.push(format!(
+                "{rule}: the BR {br} is inside the rule's own prefix"
+            ));
+        }
+        // Every PSID gets the same share: all but the excluded ranges, split 2^k ways
+        let (a, k) = (rule.bmr.psid_offset, rule.bmr.psid_len());
+        let ranges = (1u32 << a) - if a == 0 { 0 } else { 1 };
+        let expected = ranges << (16 - a - k);
+        for psid in [0, ((1u32 << k) - 1) as u16] {
+            let ports: u32 = crate::port_ranges(psid, a, k)
+                .iter()
+                .map(|(start, end)| (end - start) as u32 + 1)
+                .sum();
+            if ports != expected {
+                problems.push(format!(
+                    "{rule}: PSID {psid} gets {ports} ports instead of {expected}"
+                ));
+            }
+        }
+        if rule.ce_layout == CeLayout::Jpne && expected != 240 {
+            problems.push(format!(
+                "{rule}: v6plus-style rules give each PSID 240 ports, this one {expected}"
+            ));
+        }
+    }
+    for (i, a) in rules.iter().enumerate() {
+        for b in &rules[i + 1..] {
+            if a.prefix() == b.prefix() {
+                problems.push(format!("{a} and {b} are for the same prefix"));
+            } else if a.prefix().contains(&b.prefix()) || b.prefix().contains(&a.prefix()) {
+                let (outer, inner) = if a.bmr.ipv6_len < b.bmr.ipv6_len {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                // A fixed address on a line that's in a MAP-E prefix is what fixed rules are for
+                let addr = inner.bmr.ipv6_prefix;
+                let mapped = |r: &Rule| r.bmr.apply(addr).map(|m| (m.ipv4, m.psid));
+                if !inner.fixed()
+                    && (mapped(outer) != mapped(inner) || outer.br_addr != inner.br_addr)
+                {
+                    problems.push(format!(
+                        "{inner} overrides {outer} with a different mapping or BR"
+                    ));
+                }
+            } else if a.ipv4_prefix().contains(&b.ipv4_prefix())
+                || b.ipv4_prefix().contains(&a.ipv4_prefix())
+            {
+                problems.push(format!(
+                    "{a} and {b} map different IPv6 prefixes onto the same IPv4 addresses"
+                ));
+            }
+        }
+    }
+    problems
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.prefix(), self.ipv4_prefix())
+    }
+}
@@ -0,0 +1,180 @@
+// JSON Schemas for everything this binary reads or writes as JSON, so front-ends can validate
+// against them. The version only goes up on incompatible changes; adding optional fields doesn't
+// count.
+
+use clap::Parser;
+use serde_json::{json, Value};
+
+const VERSION: u32 = 1;
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Name {
+    /// The calculation, as plugins get it on stdin
+    MapeData,
+    /// `rules list --json`
+    RulesList,
+    /// A `--rules` file in JSON (TOML files have the same shape)
+    RulesFile,
+}
+
+#[derive(Parser)]
+pub struct Schema {
+    #[arg(
+        value_enum,
+        help = "Print only this schema; by default all are printed, by name"
+    )]
+    name: Option<Name>,
+}
+
+impl Schema {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let out = match self.name {
+            Some(name) => schema(name),
+            None => json!({
+                "mape-data": schema(Name::MapeData),
+                "rules-list": schema(Name::RulesList),
+                "rules-file": schema(Name::RulesFile),
+            }),
+        };
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        Ok(())
+    }
+}
+
+fn string(format: &str) -> Value {
+    json!({ "type": "string", "format": format })
+}
+
+fn int(max: u32) -> Value {
+    json!({ "type": "integer", "minimum": 0, "maximum": max })
+}
+
+fn schema(name: Name) -> Value {
+    let (id, title, body) = match name {
+        Name::MapeData => (
+            "mape-data",
+            "MAP-E calculation",
+            json!({
+                "type": "object",
+                "required": [
+                    "addr", "ipv4_addr", "br_addr", "edge_addr", "psid", "port_ranges",
+                    "rule_prefix", "rule_ipv4_prefix", "ea_len", "psid_offset", "psid_len",
+                    "share_ratio", "dmr", "fmrs",
+                ],
+                "properties": {
+                    "addr": string("ipv6"),
+                    "ipv4_addr": string("ipv4"),
+                    "br_addr": string("ipv6"),
+                    "edge_addr": string("ipv6"),
+                    "psid": int(u16::MAX as u32),
+                    "port_ranges": {
+                        "type": "array",
+                        "description": "Inclusive [start, end] port ranges",
+                        "items": {
+                            "type": "array",
+                            "items": int(u16::MAX as u32),
+                            "minItems": 2,
+                            "maxItems": 2,
+                        },
+                    },
+                    "rule_prefix": { "type": "string" },
+                    "rule_ipv4_prefix": { "type": "string" },
+                    "ea_len": int(64),
+                    "psid_offset": int(16),
+                    "psid_len": int(16),
+                    "share_ratio": {
+                        "type": "integer",
+                        "description": "Users sharing the IPv4 address, 2 to the PSID length",
+                        "minimum": 1,
+                    },
+                    "dmr": {
+                        "type": "string",
+                        "description": "Default Mapping Rule: the BR's address, as a /128",
+                    },
+                    "fmrs": {
+                        "type": "array",
+                        "description": "Forwarding Mapping Rules, from DHCPv6; empty otherwise",
+                        "items": {
+                            "type": "object",
+                            "required": ["prefix", "ipv4_prefix", "ea_len", "psid_offset"],
+                            "properties": {
+                                "prefix": { "type": "string" },
+                                "ipv4_prefix": { "type": "string" },
+                                "ea_len": int(64),
+                                "psid_offset": int(16),
+                            },
+                        },
+                    },
+                },
+            }),
+        ),
+        Name::RulesList => (
+            "rules-list",
+            "Mapping rules in effect",
+            json!({
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": [
+                        "ipv6_prefix", "ipv4_prefix", "ea_len", "psid_offset", "psid_len",
+                        "br_addr", "ce_layout", "confidence", "source",
+                    ],
+                    "properties": {
+                        "ipv6_prefix": { "type": "string" },
+                        "ipv4_prefix": { "type": "string" },
+                        "ea_len": int(64),
+                        "psid_offset": int(16),
+                        "psid_len": int(16),
+                        "br_addr": string("ipv6"),
+                        "ce_layout": { "enum": ["jpne", "rfc7597"] },
+                        "confidence": { "enum": ["inferred", "reported", "confirmed"] },
+                        "source": { "type": "string" },
+                    },
+                },
+            }),
+        ),
+        Name::RulesFile => (
+            "rules-file",
+            "Mapping rules file",
+            json!({
+                "type": "object",
+                "required": ["rule"],
+                "additionalProperties": false,
+                "properties": {
+                    "rule": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["prefix", "ipv4_prefix"],
+                            "additionalProperties": false,
+                            "properties": {
+                                "prefix": { "type": "string" },
+                                "ipv4_prefix": { "type": "string" },
+                                "br": string("ipv6"),
+                                "ea_len": int(64),
+                                "psid_offset": int(16),
+                                "ce_layout": { "enum": ["jpne", "rfc7597"] },
+                                "confidence": { "enum": ["inferred", "reported", "confirmed"] },
+                                "source": { "type": "string" },
+                                "fixed": {
+                                    "type": "boolean",
+                                    "description": "A fixed IPv4 address service: a /32 with every port",
+                                },
+                            },
+                        },
+                    },
+                },
+            }),
+        ),
+    };
+    let mut schema = json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("https://github.com/euank/v6plus-tun/schema/v{VERSION}/{id}.json"),
+        "title": title,
+    });
+    schema
+        .as_object_mut()
+        .unwrap()
+        .extend(body.as_object().unwrap().clone());
+    schema
+}
@@ -0,0 +1,167 @@
+// Counters kept on this machine only, for judging how stable the line is over months: how often
+// setup-linux has (re)applied the config, how often the prefix changed under it, and how long
+// the tunnel has been up. Nothing is sent anywhere.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use clap::Parser;
+
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/v6plus-tun";
+
+// Times are seconds since the epoch
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+struct Stats {
+    setups: u64,
+    first_setup: Option<u64>,
+    last_setup: Option<u64>,
+    tun_dev: Option<String>,
+    // The end-user prefix of the last setup, and when it was first seen
+    prefix: Option<ipnet::Ipv6Net>,
+    prefix_since: Option<u64>,
+    prefix_changes: u64,
+    // When the tunnel in use came up, and when the one before it went: replaced by a setup, or
+    // found gone by one (by the boot, if a reboot took it)
+    tunnel_up_since: Option<u64>,
+    tunnel_last_down: Option<u64>,
+}
+
+fn path(state_dir: &std::path::Path) -> std::path::PathBuf {
+    state_dir.join("stats.json")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn load(state_dir: &std::path::Path) -> anyhow::Result<Stats> {
+    let path = path(state_dir);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => {
+            serde_json::from_str(&text).with_context(|| format!("{} is not valid", path.display()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Stats::default()),
+        Err(e) => Err(e).with_context(|| format!("could not read {}", path.display())),
+    }
+}
+
+// When the machine booted, from /proc/stat's "btime 1700000000" line
+fn boot_time() -> Option<u64> {
+    std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|l| l.strip_prefix("btime ")?.trim().parse().ok())
+}
+
+// Counts a successful setup, which created the tunnel (or replaced one) if `new_tunnel`, and
+// otherwise reused the one already there. Written to a temporary file first, so a crash midway
+// can't lose the months of history already there.
+pub fn record(
+    state_dir: &std::path::Path,
+    tun_dev: &str,
+    prefix: ipnet::Ipv6Net,
+    new_tunnel: bool,
+) -> anyhow::Result<()> {
+    let mut stats = load(state_dir)?;
+    let now = now();
+    let boot = boot_time().unwrap_or(0);
+    stats.setups += 1;
+    stats.first_setup.get_or_insert(now);
+    stats.last_setup = Some(now);
+    // A reused tunnel has been up since it was recorded, unless it's not the one recorded: another
+    // device, or one something else recreated after a reboot
+    let same = stats.tun_dev.as_deref() == Some(tun_dev)
+        && stats.tunnel_up_since.is_some_and(|since| since >= boot);
+    if new_tunnel || !same {
+        if let Some(since) = stats.tunnel_up_since {
+            stats.tunnel_last_down = Some(if since < boot { boot } else { now });
+        }
+        stats.tunnel_up_since = Some(now);
+    }
+    stats.tun_dev = Some(tun_dev.to_string());
+    if stats.prefix != Some(prefix) {
+        if stats.prefix.is_some() {
+            stats.prefix_changes += 1;
+        }
+        stats.prefix = Some(prefix);
+        stats.prefix_since = Some(now);
+    }
+    std::fs::create_dir_all(state_dir)
+        .with_context(|| format!("could not create {}", state_dir.display()))?;
+    let path = path(state_dir);
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(&stats)?)
+        .with_context(|| format!("could not write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path).with_context(|| format!("could not replace {}", path.display()))
+}
+
+// Coarse on purpose, the interesting spans are days to months
+fn ago(then: u64) -> String {
+    let secs = now().saturating_sub(then);
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    if days > 0 {
+        format!("{days}d {hours}h")
+    } else if hours > 0 {
+        format!("{hours}h {mins}m")
+    } else {
+        format!("{mins}m")
+    }
+}
+
+#[derive(Parser)]
+pub struct ShowStats {
+    #[arg(
+        long,
+        default_value = DEFAULT_STATE_DIR,
+        help = "Directory setup-linux keeps its statistics in"
+    )]
+    state_dir: std::path::PathBuf,
+}
+
+impl ShowStats {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let stats = load(&self.state_dir)?;
+        let (Some(first), Some(last)) = (stats.first_setup, stats.last_setup) else {
+            println!(
+                "No setups recorded in {} yet",
+                path(&self.state_dir).display()
+            );
+            return Ok(());
+        };
+        println!(
+            "Setups: {} over {}, the last {} ago",
+            stats.setups,
+            ago(first),
+            ago(last)
+        );
+        if let (Some(prefix), Some(since)) = (stats.prefix, stats.prefix_since) {
+            println!(
+                "Prefix: {prefix} for {}, {} change(s) seen",
+                ago(since),
+                stats.prefix_changes
+            );
+        }
+        if let Some(tun_dev) = &stats.tun_dev {
+            // IFF_UP. Flaps in between aren't seen, so this is as of the last setup at best.
+            let up = std::fs::read_to_string(format!("/sys/class/net/{tun_dev}/flags"))
+                .ok()
+                .and_then(|f| u32::from_str_radix(f.trim().trim_start_matches("0x"), 16).ok())
+                .is_some_and(|flags| flags & 1 != 0);
+            // Anything up since before the last boot has been recreated since, by something else
+            let since = stats
+                .tunnel_up_since
+                .map(|since| since.max(boot_time().unwrap_or(0)));
+            match since {
+                Some(since) if up => println!("Tunnel {tun_dev}: up for {}", ago(since)),
+                _ if up => println!("Tunnel {tun_dev}: up, set up {} ago", ago(last)),
+                _ => println!("Tunnel {tun_dev}: down or gone"),
+            }
+            if let Some(down) = stats.tunnel_last_down {
+                println!("Last torn down or replaced {} ago", ago(down));
+            }
+        }
+        Ok(())
+    }
+}
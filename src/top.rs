@@ -0,0 +1,86 @@
+// A live view of new NAT allocations, from conntrack's event stream. When ports run out it's
+// usually one host opening connections far faster than everyone else, and this makes that host
+// obvious.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::BufRead;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use clap::Parser;
+
+use crate::traffic::Flow;
+use crate::AddrArgs;
+
+#[derive(Parser)]
+pub struct NatTop {
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long,
+        default_value_t = 60,
+        help = "Seconds over which to count each host's new connections"
+    )]
+    window: u64,
+}
+
+impl NatTop {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let nat_addr = data.ipv4_addr.to_string();
+        let window = Duration::from_secs(self.window);
+
+        let mut child = std::process::Command::new("conntrack")
+            .args(["-E", "-e", "NEW"])
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .context("could not run conntrack")?;
+        let events = std::io::BufReader::new(child.stdout.take().unwrap());
+
+        // Recent connection times per LAN host
+        let mut recent: HashMap<String, VecDeque<Instant>> = HashMap::new();
+        println!(
+            "{:<15} {:<21} {:>5} {:>5} {:>8}",
+            "HOST",
+            "DESTINATION",
+            "RANGE",
+            "PORT",
+            format!("LAST {}S", self.window)
+        );
+        for line in events.lines() {
+            let Some(flow) = Flow::parse(&line?) else {
+                continue;
+            };
+            // Only flows that went out of the tunnel, not e.g. LAN-to-router ones
+            if flow.nat_addr != nat_addr {
+                continue;
+            }
+            let now = Instant::now();
+            let times = recent.entry(flow.src.clone()).or_default();
+            times.push_back(now);
+            while times.front().is_some_and(|t| now - *t > window) {
+                times.pop_front();
+            }
+            let range = data
+                .port_ranges
+                .iter()
+                .position(|(start, end)| (*start..=*end).contains(&flow.nat_port))
+                .map(|i| (i + 1).to_string())
+                .unwrap_or_else(|| "?".to_string());
+            println!(
+                "{:<15} {:<21} {:>5} {:>5} {:>8}",
+                flow.src,
+                format!("{}:{}", flow.dst, flow.dport),
+                range,
+                flow.nat_port,
+                times.len()
+            );
+        }
+
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("conntrack exited with {status}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+// Send test flows through the live configuration and check what the kernel actually did with
+// them, by reading back conntrack. HMARK's hash is seeded, so we can't predict which range a given
+// flow lands in (and its mark isn't visible in conntrack, which only shows the connmark), but we
+// can check every flow got a port we own and how evenly flows spread across ranges.
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use cmd_lib::run_fun;
+
+use crate::AddrArgs;
+
+#[derive(Parser)]
+pub struct GenTraffic {
+    #[command(flatten)]
+    input: AddrArgs,
+    #[arg(
+        long,
+        default_value = "198.51.100.1:9",
+        help = "Where to send UDP test flows; the default is the lab's fake BR"
+    )]
+    target: std::net::SocketAddrV4,
+    #[arg(long, default_value_t = 200, help = "Number of flows to send")]
+    count: usize,
+}
+
+// The parts of a conntrack entry we care about
+pub struct Flow {
+    pub src: String,
+    pub dst: String,
+    pub sport: u16,
+    pub dport: u16,
+    // Destination address and port of the reply direction, i.e. what we were SNAT'd to
+    pub nat_addr: String,
+    pub nat_port: u16,
+}
+
+impl Flow {
+    // Parses a line of `conntrack -L` output, such as:
+    // udp 17 29 src=192.168.1.2 dst=198.51.100.1 sport=40000 dport=9 [UNREPLIED] src=198.51.100.1
+    //     dst=106.72.171.205 sport=9 dport=7925 mark=0 use=1
+    // Event lines from `conntrack -E` are the same, with an "[NEW]" or similar in front.
+    pub fn parse(line: &str) -> Option<Flow> {
+        let field = |name: &str, nth: usize| {
+            line.split_whitespace()
+                .filter_map(|t| t.strip_prefix(name))
+                .nth(nth)
+        };
+        Some(Flow {
+            src: field("src=", 0)?.to_string(),
+            dst: field("dst=", 0)?.to_string(),
+            sport: field("sport=", 0)?.parse().ok()?,
+            dport: field("dport=", 0)?.parse().ok()?,
+            nat_addr: field("dst=", 1)?.to_string(),
+            nat_port: field("dport=", 1)?.parse().ok()?,
+        })
+    }
+}
+
+impl GenTraffic {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let data = self.input.calculate()?;
+        let mut sports = std::collections::HashSet::new();
+        for _ in 0..self.count {
+            let sock = std::net::UdpSocket::bind("0.0.0.0:0")?;
+            sock.send_to(b"v6plus-tun gen-traffic", self.target)
+                .with_context(|| format!("could not send to {}", self.target))?;
+            sports.insert(sock.local_addr()?.port());
+        }
+
+        let (dst, dport) = (self.target.ip(), self.target.port());
+        let out = run_fun!(conntrack -L -p udp --orig-dst $dst --dport $dport 2>/dev/null)
+            .context("could not read conntrack table")?;
+        let flows: Vec<Flow> = out
+            .lines()
+            .filter_map(Flow::parse)
+            .filter(|f| sports.contains(&f.sport))
+            .collect();
+
+        let mut per_range: HashMap<usize, usize> = HashMap::new();
+        let mut bad = 0;
+        for flow in &flows {
+            let range = data
+                .port_ranges
+                .iter()
+                .position(|(start, end)| (*start..=*end).contains(&flow.nat_port));
+            match range {
+                Some(i) => *per_range.entry(i).or_default() += 1,
+                None => {
+                    println!(
+                        "sport {} was sent from port {}, which isn't ours",
+                        flow.sport, flow.nat_port
+                    );
+                    bad += 1;
+                }
+            }
+        }
+
+        println!(
+            "{} flows sent, {} found in conntrack",
+            sports.len(),
+            flows.len()
+        );
+        for (i, (start, end)) in data.port_ranges.iter().enumerate() {
+            let n = per_range.get(&i).copied().unwrap_or(0);
+            println!("  {start}-{end}: {n}");
+        }
+        if bad > 0 {
+            bail!("{bad} flows were not SNAT'd as expected");
+        }
+        Ok(())
+    }
+}
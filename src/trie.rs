@@ -0,0 +1,108 @@
+//! A binary trie over IPv6 prefixes, for finding which rule an address falls under.
+
+use std::net::Ipv6Addr;
+
+/// Maps IPv6 prefixes to values, answering longest-prefix-match queries.
+///
+/// Overlapping prefixes are fine: the most specific one covering an address wins. Inserting the
+/// same prefix twice keeps the first value, so callers control precedence with insertion order.
+#[derive(Debug)]
+pub struct PrefixTrie<T> {
+    // nodes[0] is the root, the /0
+    nodes: Vec<Node<T>>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    children: [Option<usize>; 2],
+    value: Option<T>,
+}
+
+fn bit(addr: Ipv6Addr, i: u8) -> usize {
+    ((u128::from(addr) >> (127 - i)) & 1) as usize
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        PrefixTrie {
+            nodes: vec![Node {
+                children: [None, None],
+                value: None,
+            }],
+        }
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `prefix/len`. Bits of `prefix` past `len` are ignored. Returns false, leaving the
+    /// trie unchanged, if the prefix was already present.
+    pub fn insert(&mut self, prefix: Ipv6Addr, len: u8, value: T) -> bool {
+        assert!(len <= 128, "prefix length over 128");
+        let mut node = 0;
+        for i in 0..len {
+            let b = bit(prefix, i);
+            node = match self.nodes[node].children[b] {
+                Some(child) => child,
+                None => {
+                    self.nodes.push(Node {
+                        children: [None, None],
+                        value: None,
+                    });
+                    let child = self.nodes.len() - 1;
+                    self.nodes[node].children[b] = Some(child);
+                    child
+                }
+            };
+        }
+        if self.nodes[node].value.is_some() {
+            return false;
+        }
+        self.nodes[node].value = Some(value);
+        true
+    }
+
+    /// The value of the longest prefix covering `addr`, with that prefix's length
+    pub fn lookup(&self, addr: Ipv6Addr) -> Option<(u8, &T)> {
+        let mut node = 0;
+        let mut best = self.nodes[0].value.as_ref().map(|v| (0, v));
+        for i in 0..128 {
+            match self.nodes[node].children[bit(addr, i)] {
+                Some(child) => node = child,
+                None => break,
+            }
+            if let Some(v) = &self.nodes[node].value {
+                best = Some((i + 1, v));
+            }
+        }
+        best
+    }
+
+    /// The value whose prefix shares the most leading bits with `addr`, for suggesting what an
+    /// unmatched address may have been meant to match. Among equally close prefixes the shortest
+    /// wins, then the numerically lowest.
+    pub fn nearest(&self, addr: Ipv6Addr) -> Option<&T> {
+        // Go as deep along addr as the trie allows, then take the closest value below
+        let mut node = 0;
+        for i in 0..128 {
+            match self.nodes[node].children[bit(addr, i)] {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        let mut level = vec![node];
+        while !level.is_empty() {
+            if let Some(v) = level.iter().find_map(|&n| self.nodes[n].value.as_ref()) {
+                return Some(v);
+            }
+            level = level
+                .iter()
+                .flat_map(|&n| self.nodes[n].children.iter().flatten().copied())
+                .collect();
+        }
+        None
+    }
+}
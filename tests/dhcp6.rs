@@ -0,0 +1,85 @@
+// Checks of reading MAP-E options from a DHCPv6 message, through the CLI as a lease file. The
+// message comes off the network, so malformed options have to fail cleanly rather than panic.
+
+use std::process::Command;
+
+fn option(code: u16, data: &[u8]) -> Vec<u8> {
+    [
+        &code.to_be_bytes(),
+        &(data.len() as u16).to_be_bytes(),
+        data,
+    ]
+    .concat()
+}
+
+// A Reply delegating 2001:db8:12:3400::/56, with the given S46 rule and a BR
+fn reply(rule: &[u8]) -> Vec<u8> {
+    let mut prefix = vec![0, 0, 0x0e, 0x10, 0, 0, 0x1c, 0x20, 56];
+    prefix.extend([
+        0x20, 0x01, 0x0d, 0xb8, 0, 0x12, 0x34, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ]);
+    let ia_pd = [&[0u8; 12][..], &option(26, &prefix)].concat();
+    let br = [
+        0x20, 0x01, 0x0d, 0xb8, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+    ];
+    let mape = [option(89, rule), option(90, &br)].concat();
+    [vec![7, 1, 2, 3], option(25, &ia_pd), option(94, &mape)].concat()
+}
+
+// Runs calculate --lease on the message, giving (exit code, stdout, stderr)
+fn calculate(name: &str, msg: &[u8]) -> (Option<i32>, String, String) {
+    let path = std::env::temp_dir().join(format!(
+        "v6plus-tun-test-{}-{name}.lease6",
+        std::process::id()
+    ));
+    std::fs::write(&path, msg).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_v6plus-tun"))
+        .arg("calculate")
+        .arg("--lease")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    (
+        out.status.code(),
+        String::from_utf8_lossy(&out.stdout).into_owned(),
+        String::from_utf8_lossy(&out.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn rule_from_reply() {
+    // FMR flag, 16 EA bits, 192.0.2.0/24, 2001:db8::/40
+    let rule = [1, 16, 24, 192, 0, 2, 0, 40, 0x20, 0x01, 0x0d, 0xb8, 0];
+    let (code, out, err) = calculate("good", &reply(&rule));
+    assert_eq!(code, Some(0), "{err}");
+    assert!(out.contains("Rule: 2001:db8::/40 -> 192.0.2.0/24"), "{out}");
+    assert!(
+        out.contains("IPv4 Addr (CE IPv4 Address): 192.0.2.18"),
+        "{out}"
+    );
+    assert!(out.contains("PSID Offset/Length: 6/8"), "{out}");
+    assert!(out.contains("FMR: 2001:db8::/40"), "{out}");
+}
+
+#[test]
+fn truncated_rule() {
+    let (code, _, err) = calculate("short", &reply(&[0, 16, 24, 192, 0]));
+    assert_eq!(code, Some(1), "{err}");
+    assert!(err.contains("S46 rule is truncated"), "{err}");
+
+    // A /40 needs 5 bytes of prefix
+    let rule = [0, 16, 24, 192, 0, 2, 0, 40, 0x20, 0x01];
+    let (code, _, err) = calculate("short-prefix", &reply(&rule));
+    assert_eq!(code, Some(1), "{err}");
+    assert!(err.contains("S46 rule prefix is truncated"), "{err}");
+}
+
+#[test]
+fn overlong_rule_prefix() {
+    // 200 bits would be 25 bytes of prefix, more than an IPv6 address holds
+    let rule = [[0, 16, 24, 192, 0, 2, 0, 200].as_slice(), &[0xff; 25]].concat();
+    let (code, _, err) = calculate("long", &reply(&rule));
+    assert_eq!(code, Some(1), "{err}");
+    assert!(err.contains("S46 rule prefix length over 128"), "{err}");
+}
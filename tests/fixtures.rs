@@ -0,0 +1,39 @@
+// The fixtures have to agree with the math they describe, or they'd mislead whoever checks
+// against them
+
+use v6plus_tun::ce_from_parts;
+use v6plus_tun::fixtures::FIXTURES;
+
+#[test]
+fn fixtures_match_the_math() {
+    for f in FIXTURES {
+        f.bmr.validate().unwrap();
+        let mapping = f.bmr.apply(f.addr).unwrap();
+        assert_eq!(
+            (mapping.ipv4, mapping.psid, mapping.psid_len),
+            (f.ipv4, f.psid, f.psid_len),
+            "{}",
+            f.family
+        );
+        let ce = if f.jpne_ce {
+            ce_from_parts(f.addr, mapping.ipv4, mapping.psid as u8)
+        } else {
+            mapping.ce
+        };
+        assert_eq!(ce, f.ce, "{}", f.family);
+        assert_eq!(mapping.port_ranges[0], f.first_port_range, "{}", f.family);
+        assert_eq!(
+            mapping.port_ranges.len(),
+            f.port_range_count,
+            "{}",
+            f.family
+        );
+        let prefix_len = f.bmr.ipv6_len + f.bmr.ea_len;
+        assert_eq!(
+            f.bmr.end_user_prefix(f.ipv4, f.psid),
+            Some(ipnet::Ipv6Net::new(f.addr, prefix_len).unwrap().network()),
+            "{}",
+            f.family
+        );
+    }
+}
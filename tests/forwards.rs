@@ -0,0 +1,81 @@
+// Checks of check-forwards through the CLI, for a v6plus address with PSID 0xef, whose first
+// range is 7920-7935
+
+use std::process::Command;
+
+const ADDR: &str = "240b:10:abcd:ef00::1";
+
+// Runs check-forwards on the CSV, giving (exit code, stdout, stderr, the remapped CSV)
+fn check_forwards(name: &str, csv: &str) -> (Option<i32>, String, String, String) {
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("v6plus-tun-test-{}-{name}.csv", std::process::id()));
+    let output = input.with_extension("out.csv");
+    std::fs::write(&input, csv).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_v6plus-tun"))
+        .args(["check-forwards", ADDR, "--csv"])
+        .arg(&input)
+        .arg("--output")
+        .arg(&output)
+        .output()
+        .unwrap();
+    let remapped = std::fs::read_to_string(&output).unwrap_or_default();
+    std::fs::remove_file(&input).unwrap();
+    let _ = std::fs::remove_file(&output);
+    (
+        out.status.code(),
+        String::from_utf8_lossy(&out.stdout).into_owned(),
+        String::from_utf8_lossy(&out.stderr).into_owned(),
+        remapped,
+    )
+}
+
+#[test]
+fn keeps_moves_and_loses_forwards() {
+    let csv = "\
+name,proto,external_port,internal_host,internal_port
+game,tcp,7920-7923,192.168.1.10,7920-7923
+web,tcp,8080,192.168.1.20,80
+rtp,udp,10000-10031,192.168.1.30,10000-10031
+";
+    let (code, out, err, remapped) = check_forwards("mixed", csv);
+    assert_eq!(code, Some(1));
+    assert!(err.contains("1 forwards can't be kept"), "{err}");
+    assert!(
+        out.contains("ok:    game (tcp 7920-7923) is on owned ports"),
+        "{out}"
+    );
+    // Moved to the first owned port the kept forward doesn't use
+    assert!(
+        out.contains("move:  web (tcp 8080) isn't ours, use 7924 instead"),
+        "{out}"
+    );
+    // 32 ports can't fit in one 16-port range
+    assert!(out.contains("lost:  rtp (udp 10000-10031)"), "{out}");
+    assert!(
+        out.contains("Keep these out of SNAT with: --no-snat-port 7920-7923 --no-snat-port 7924"),
+        "{out}"
+    );
+    assert_eq!(
+        remapped,
+        "\
+name,proto,external_port,internal_host,internal_port
+game,tcp,7920-7923,192.168.1.10,7920-7923
+web,tcp,7924,192.168.1.20,80
+"
+    );
+}
+
+#[test]
+fn rejects_bad_lines() {
+    let (code, _, err, _) = check_forwards("short", "web,tcp,8080\n");
+    assert_eq!(code, Some(1));
+    assert!(err.contains("line 1"), "{err}");
+    assert!(err.contains("expected 5 fields, got 3"), "{err}");
+
+    let (code, _, err, _) = check_forwards("sizes", "web,tcp,8080-8081,192.168.1.20,80\n");
+    assert_eq!(code, Some(1));
+    assert!(
+        err.contains("external and internal port ranges are different sizes"),
+        "{err}"
+    );
+}
@@ -0,0 +1,83 @@
+// Checks of the calculation as the CLI does it, through the built-in rules
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use v6plus_tun::mape::MapEData;
+use v6plus_tun::rules;
+
+#[test]
+fn v6plus_from_addr() {
+    let addr: Ipv6Addr = "240b:10:abcd:ef00::1".parse().unwrap();
+    let data = MapEData::from_addr(addr).unwrap();
+    assert_eq!(data.ipv4_addr, Ipv4Addr::new(106, 72, 171, 205));
+    assert_eq!(data.psid, 0xef);
+    assert_eq!(
+        data.edge_addr,
+        "240b:10:abcd:ef00:6a:48ab:cd00:ef00"
+            .parse::<Ipv6Addr>()
+            .unwrap()
+    );
+    assert_eq!(
+        data.br_addr,
+        "2404:9200:225:100::64".parse::<Ipv6Addr>().unwrap()
+    );
+    assert_eq!(data.dmr.addr(), data.br_addr);
+    assert_eq!(data.dmr.prefix_len(), 128);
+    assert_eq!(data.share_ratio, 256);
+    assert_eq!(data.port_ranges.len(), 15);
+    assert_eq!(data.port_ranges[0], (7920, 7935));
+    assert!(data.fmrs.is_empty());
+    assert_eq!(data.ipv4_doubt, None);
+}
+
+#[test]
+fn unknown_prefix_suggests_nearest() {
+    let err = MapEData::from_addr("2400:1::1".parse().unwrap()).unwrap_err();
+    assert!(
+        err.to_string().starts_with("unknown prefix: 2400:1"),
+        "{err}"
+    );
+    assert!(err.to_string().contains("nearest known rule"), "{err}");
+}
+
+#[test]
+fn lookup_finds_builtin_rule() {
+    let addr: Ipv6Addr = "240b:10:abcd:ef00::1".parse().unwrap();
+    let rule = rules::lookup(addr).unwrap();
+    assert!(rules::RULES
+        .iter()
+        .any(|r| r.prefix() == rule.prefix() && r.br_addr == rule.br_addr));
+    assert!(rule.prefix().contains(&addr));
+    assert_eq!(rule.ipv4_doubt(Ipv4Addr::new(106, 72, 171, 205)), None);
+    assert!(rule.ipv4_doubt(Ipv4Addr::new(10, 0, 0, 1)).is_some());
+}
+
+// What front-ends get as JSON has to read back into the same parameters
+#[test]
+fn json_round_trip() {
+    let data = MapEData::from_addr("2404:7a84:1:2300::".parse().unwrap()).unwrap();
+    let json = serde_json::to_string(&data).unwrap();
+    assert!(!json.contains("ipv4_doubt"));
+    let back: MapEData = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.addr, data.addr);
+    assert_eq!(back.ipv4_addr, data.ipv4_addr);
+    assert_eq!(back.edge_addr, data.edge_addr);
+    assert_eq!(back.br_addr, data.br_addr);
+    assert_eq!(back.port_ranges, data.port_ranges);
+    assert_eq!(back.to_string(), data.to_string());
+}
+
+// A rules file entry for an allocation the built-in table doesn't have is reported or confirmed,
+// so only a guessed one is doubted for landing outside the built-in prefixes
+#[test]
+fn unknown_jpne_allocation_doubted_only_when_inferred() {
+    let mut rule = *rules::lookup("240b:10::1".parse().unwrap()).unwrap();
+    rule.bmr.ipv4_prefix = Ipv4Addr::new(203, 0, 0, 0);
+    let ipv4 = Ipv4Addr::new(203, 0, 171, 205);
+    for confidence in [rules::Confidence::Reported, rules::Confidence::Confirmed] {
+        rule.confidence = confidence;
+        assert_eq!(rule.ipv4_doubt(ipv4), None);
+    }
+    rule.confidence = rules::Confidence::Inferred;
+    assert!(rule.ipv4_doubt(ipv4).is_some());
+}
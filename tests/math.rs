@@ -0,0 +1,211 @@
+// Exhaustive checks of the MAP-E math. The input spaces are small enough (256 PSIDs, a handful
+// of offset/length combinations) that there's no need to sample.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use v6plus_tun::{ce_from_parts, port_ranges, psid_for_port, psid_from_addr, Bmr};
+
+#[test]
+fn v6plus_ranges_shape() {
+    for psid in 0..=255u16 {
+        let ranges = port_ranges(psid, 4, 8);
+        assert_eq!(ranges.len(), 15);
+        for (i, (start, end)) in ranges.iter().enumerate() {
+            assert_eq!(end - start, 15);
+            assert_eq!(start >> 12, i as u16 + 1);
+            assert_eq!((start >> 4) & 0xff, psid);
+        }
+    }
+}
+
+// For every offset/length, the PSIDs' port sets must be disjoint and together cover every port
+// outside the excluded low range.
+#[test]
+fn psids_partition_ports() {
+    for a in 0..=6u8 {
+        for k in 0..=(16 - a) {
+            let mut owner = vec![None; 65536];
+            for psid in 0..(1u32 << k) {
+                for (start, end) in port_ranges(psid as u16, a, k) {
+                    for port in start..=end {
+                        assert_eq!(owner[port as usize], None, "a={a} k={k} port {port}");
+                        owner[port as usize] = Some(psid);
+                    }
+                }
+            }
+            let excluded = if a == 0 { 0 } else { 1usize << (16 - a) };
+            for (port, owner) in owner.iter().enumerate() {
+                assert_eq!(owner.is_some(), port >= excluded, "a={a} k={k} port {port}");
+            }
+        }
+    }
+}
+
+#[test]
+fn psid_for_port_reverses_ranges() {
+    for a in 0..=6u8 {
+        for k in 0..=(16 - a) {
+            let mut owned = 0;
+            for psid in 0..(1u32 << k) {
+                for (start, end) in port_ranges(psid as u16, a, k) {
+                    for port in start..=end {
+                        assert_eq!(psid_for_port(port, a, k), Some(psid as u16));
+                        owned += 1;
+                    }
+                }
+            }
+            let unowned = (0..=u16::MAX)
+                .filter(|&p| psid_for_port(p, a, k).is_none())
+                .count();
+            assert_eq!(owned + unowned, 65536, "a={a} k={k}");
+        }
+    }
+}
+
+#[test]
+fn ce_embeds_parts() {
+    let base: Ipv6Addr = "240b:10::".parse().unwrap();
+    for psid in 0..=255u8 {
+        for third in [0u8, 1, 127, 255] {
+            let ipv4 = Ipv4Addr::new(106, 72, third, psid ^ third);
+            let ce = ce_from_parts(base, ipv4, psid);
+            let segs = ce.segments();
+            assert_eq!(&segs[..2], &base.segments()[..2]);
+            assert_eq!(psid_from_addr(ce), psid);
+            // The interface ID carries the IPv4 address and PSID again
+            let octets = ce.octets();
+            assert_eq!(octets[8], 0);
+            assert_eq!(&octets[9..13], &ipv4.octets());
+            assert_eq!(octets[13], 0);
+            assert_eq!(octets[14], psid);
+            assert_eq!(octets[15], 0);
+        }
+    }
+}
+
+#[test]
+fn known_example() {
+    // Matches the output of http://ipv4.web.fc2.com/map-e.html
+    let addr: Ipv6Addr = "240b:10:abcd:ef00::1".parse().unwrap();
+    let psid = psid_from_addr(addr);
+    assert_eq!(psid, 0xef);
+    assert_eq!(
+        ce_from_parts(addr, Ipv4Addr::new(106, 72, 0xab, 0xcd), psid),
+        "240b:10:abcd:ef00:6a:48ab:cd00:ef00"
+            .parse::<Ipv6Addr>()
+            .unwrap()
+    );
+    assert_eq!(port_ranges(psid as u16, 4, 8)[0], (7920, 7935));
+}
+
+// The generic algorithm, fed v6plus' rule, has to agree with the v6plus-specific functions on
+// everything but the CE address, whose interface ID layout differs.
+#[test]
+fn bmr_matches_v6plus() {
+    let bmr = Bmr {
+        ipv6_prefix: "240b:10::".parse().unwrap(),
+        ipv6_len: 31,
+        ipv4_prefix: Ipv4Addr::new(106, 72, 0, 0),
+        ipv4_len: 15,
+        ea_len: 25,
+        psid_offset: 4,
+    };
+    bmr.validate().unwrap();
+    for psid in 0..=255u16 {
+        for seg in [0x10u16, 0x11] {
+            let addr = Ipv6Addr::new(0x240b, seg, 0xabcd, psid << 8, 0, 0, 0, 1);
+            let mapping = bmr.apply(addr).unwrap();
+            assert_eq!(mapping.psid, psid_from_addr(addr) as u16);
+            assert_eq!(
+                mapping.ipv4,
+                Ipv4Addr::new(106, 72 | (seg as u8 & 1), 0xab, 0xcd)
+            );
+            assert_eq!(mapping.port_ranges, port_ranges(mapping.psid, 4, 8));
+        }
+    }
+}
+
+// Going from an IPv4 address and PSID back to the end-user prefix has to land on a prefix that
+// maps to them again
+#[test]
+fn bmr_end_user_prefix_round_trips() {
+    let bmr = Bmr {
+        ipv6_prefix: "240b:10::".parse().unwrap(),
+        ipv6_len: 31,
+        ipv4_prefix: Ipv4Addr::new(106, 72, 0, 0),
+        ipv4_len: 15,
+        ea_len: 25,
+        psid_offset: 4,
+    };
+    for psid in 0..=255u16 {
+        for ipv4 in [
+            Ipv4Addr::new(106, 72, 0, 0),
+            Ipv4Addr::new(106, 72, 0xab, 0xcd),
+            Ipv4Addr::new(106, 73, 0xff, 0xff),
+        ] {
+            let prefix = bmr.end_user_prefix(ipv4, psid).unwrap();
+            assert_eq!(prefix.segments()[4..], [0; 4]);
+            let mapping = bmr.apply(prefix).unwrap();
+            assert_eq!((mapping.ipv4, mapping.psid), (ipv4, psid));
+        }
+    }
+    assert_eq!(
+        bmr.end_user_prefix(Ipv4Addr::new(106, 72, 0xab, 0xcd), 0xef),
+        Some("240b:10:abcd:ef00::".parse().unwrap())
+    );
+    assert!(bmr
+        .end_user_prefix(Ipv4Addr::new(106, 74, 0, 0), 0)
+        .is_none());
+    assert!(bmr
+        .end_user_prefix(Ipv4Addr::new(106, 72, 0, 0), 256)
+        .is_none());
+}
+
+#[test]
+fn bmr_rfc7597_layout() {
+    // RFC 7597 appendix A, example 1
+    let bmr = Bmr {
+        ipv6_prefix: "2001:db8::".parse().unwrap(),
+        ipv6_len: 40,
+        ipv4_prefix: Ipv4Addr::new(192, 0, 2, 0),
+        ipv4_len: 24,
+        ea_len: 16,
+        psid_offset: 6,
+    };
+    bmr.validate().unwrap();
+    let mapping = bmr.apply("2001:db8:12:3400::".parse().unwrap()).unwrap();
+    assert_eq!(mapping.ipv4, Ipv4Addr::new(192, 0, 2, 18));
+    assert_eq!(mapping.psid, 0x34);
+    assert_eq!(mapping.psid_len, 8);
+    assert_eq!(
+        mapping.ce,
+        "2001:db8:12:3400:0:c000:212:34"
+            .parse::<Ipv6Addr>()
+            .unwrap()
+    );
+    assert!(bmr.apply("2001:db9::".parse().unwrap()).is_none());
+}
+
+// A ::/0 rule with no EA bits maps every address to the one IPv4 address, with the end-user prefix
+// empty rather than a shift by 128
+#[test]
+fn bmr_without_prefix_or_ea_bits() {
+    let bmr = Bmr {
+        ipv6_prefix: Ipv6Addr::UNSPECIFIED,
+        ipv6_len: 0,
+        ipv4_prefix: Ipv4Addr::new(192, 0, 2, 1),
+        ipv4_len: 32,
+        ea_len: 0,
+        psid_offset: 0,
+    };
+    bmr.validate().unwrap();
+    let mapping = bmr.apply("2001:db8::1".parse().unwrap()).unwrap();
+    assert_eq!(mapping.ipv4, Ipv4Addr::new(192, 0, 2, 1));
+    assert_eq!((mapping.psid, mapping.psid_len), (0, 0));
+    assert_eq!(mapping.ce, "::c000:201:0".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(mapping.port_ranges, [(0, u16::MAX)]);
+    assert_eq!(
+        bmr.end_user_prefix(Ipv4Addr::new(192, 0, 2, 1), 0),
+        Some(Ipv6Addr::UNSPECIFIED)
+    );
+}
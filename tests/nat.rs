@@ -0,0 +1,385 @@
+// Checks of the SNAT planner and the rules it renders, from the ranges PSID 0xef's 4-bit offset
+// and 8-bit PSID give: 15 ranges of 16 ports, the first being 7920-7935
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+use v6plus_tun::nat::{self, parse_pin, parse_port_range, FwRule, SnatPlan, CLAMP_FROM, MARK_BASE};
+use v6plus_tun::port_ranges;
+
+const ADDR: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+fn ranges() -> Vec<(u16, u16)> {
+    port_ranges(0xef, 4, 8)
+}
+
+#[test]
+fn buckets_in_proportion_to_range_size() {
+    // The first range keeps 12 ports, so the gcd is 4: 3 buckets for it and 4 for the others
+    let plan = SnatPlan::new(&ranges(), &[(7920, 7923)], &[]).unwrap();
+    assert_eq!(plan.ranges[0], ((7924, 7935), 3));
+    assert!(plan.ranges[1..].iter().all(|&(_, n)| n == 4));
+    assert_eq!(plan.buckets(), 3 + 14 * 4);
+    let marks = plan.marks();
+    assert_eq!(marks[0], (MARK_BASE, (7924, 7935)));
+    assert_eq!(marks[2], (MARK_BASE + 2, (7924, 7935)));
+    assert_eq!(marks[3], (MARK_BASE + 3, ranges()[1]));
+    assert_eq!(marks.last().unwrap().0, MARK_BASE + plan.buckets() - 1);
+
+    // Without exclusions it's one bucket per range
+    let plan = SnatPlan::new(&ranges(), &[], &[]).unwrap();
+    assert_eq!(plan.buckets(), 15);
+}
+
+#[test]
+fn single_excluded_port_keeps_buckets_bounded() {
+    // 5 + 10 + 14 * 16 ports has a gcd of 1, which would be 239 buckets. Rounded to units of 4
+    // ports it's about the same split in far fewer.
+    let plan = SnatPlan::new(&ranges(), &[(7925, 7925)], &[]).unwrap();
+    assert_eq!(plan.ranges[0], ((7920, 7924), 1));
+    assert_eq!(plan.ranges[1], ((7926, 7935), 3));
+    assert!(plan.ranges[2..].iter().all(|&(_, n)| n == 4));
+    assert_eq!(plan.buckets(), 1 + 3 + 14 * 4);
+    assert!(plan.buckets() <= nat::MAX_BUCKETS);
+    assert_eq!(
+        plan.rules(ADDR, None, false).len(),
+        1 + 3 * plan.buckets() + 1
+    );
+}
+
+#[test]
+fn fully_excluded_ranges_are_dropped() {
+    let plan = SnatPlan::new(&ranges(), &[(7900, 7940)], &[]).unwrap();
+    assert_eq!(plan.ranges.len(), 14);
+    assert_eq!(plan.ranges[0], (ranges()[1], 1));
+    assert_eq!(plan.buckets(), 14);
+
+    let err = SnatPlan::new(&ranges(), &[(0, u16::MAX)], &[])
+        .err()
+        .unwrap();
+    assert_eq!(err.to_string(), "no ports left to SNAT to");
+}
+
+#[test]
+fn pins_get_their_own_marks() {
+    let pin = parse_pin("-p udp --dport 3478:3481=5").unwrap();
+    let plan = SnatPlan::new(&ranges(), &[], &[pin]).unwrap();
+    assert_eq!(plan.classes.len(), 2);
+    assert!(plan.classes[0].matches.is_none());
+    assert_eq!(plan.classes[0].marks.len(), 14);
+    assert!(plan.classes[0].marks.iter().all(|&(_, r)| r != ranges()[4]));
+    assert_eq!(plan.classes[1].marks, [(MARK_BASE + 14, ranges()[4])]);
+    assert_eq!(plan.marks().len(), plan.buckets());
+
+    let err = |pin, excluded: &[(u16, u16)]| {
+        SnatPlan::new(&ranges(), excluded, &[parse_pin(pin).unwrap()])
+            .err()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(
+        err("-p tcp=16", &[]),
+        "pin '-p tcp' uses range 16, but there are only 15"
+    );
+    assert_eq!(
+        err("-p tcp=1-15", &[]),
+        "every range is pinned, leaving none for other traffic"
+    );
+    assert_eq!(
+        err("-p tcp=1", &[(7920, 7935)]),
+        "pin '-p tcp' has no ports left after exclusions"
+    );
+}
+
+#[test]
+fn parses_pins() {
+    let pin = parse_pin("-p tcp --dport 443=1-4,7").unwrap();
+    assert_eq!(pin.matches, ["-p", "tcp", "--dport", "443"]);
+    assert_eq!(pin.ranges, [0, 1, 2, 3, 6]);
+    assert_eq!(parse_pin("-p tcp").unwrap_err(), "expected MATCH=RANGES");
+    assert_eq!(parse_pin(" =1").unwrap_err(), "empty match expression");
+    assert_eq!(
+        parse_pin("-p tcp=0").unwrap_err(),
+        "ranges are numbered from 1"
+    );
+    assert!(parse_pin("-p tcp=a")
+        .unwrap_err()
+        .starts_with("bad range 'a'"));
+    assert_eq!(parse_pin("-p tcp=5-3").unwrap_err(), "range 5 is after 3");
+}
+
+#[test]
+fn one_range_needs_no_marks() {
+    let plan = SnatPlan::new(&[(0, u16::MAX)], &[], &[]).unwrap();
+    let rules = plan.rules(ADDR, None, true);
+    assert_eq!(rules.len(), 4);
+    for rule in &rules[..3] {
+        assert!(matches!(rule, FwRule::Snat { mark: None, .. }));
+    }
+    assert!(matches!(rules[3], FwRule::ClampMss { from: CLAMP_FROM }));
+    assert_eq!(nat::snat_to(ADDR, (0, u16::MAX)), "192.0.2.1");
+    assert_eq!(nat::snat_to(ADDR, (7920, 7935)), "192.0.2.1:7920-7935");
+}
+
+#[test]
+fn local_marks_skip_inbound_pins() {
+    let pins = [
+        parse_pin("-i lan1=2").unwrap(),
+        parse_pin("-p udp --dport 53=3").unwrap(),
+    ];
+    let plan = SnatPlan::new(&ranges(), &[], &pins).unwrap();
+    let local = |rules: &[FwRule]| {
+        rules
+            .iter()
+            .filter(|r| matches!(r, FwRule::Hmark { local: true, .. }))
+            .count()
+    };
+    assert_eq!(local(&plan.rules(ADDR, None, true)), 2);
+    assert_eq!(local(&plan.rules(ADDR, None, false)), 0);
+}
+
+// Two ranges of uneven size, small enough to spell out every rule
+fn small_rules() -> Vec<FwRule> {
+    let plan = SnatPlan::new(&[(1000, 1001), (2000, 2003)], &[], &[]).unwrap();
+    plan.rules(ADDR, Some(Ipv4Addr::new(192, 168, 1, 1)), true)
+}
+
+#[test]
+fn renders_iptables() {
+    let rules = small_rules();
+    let (table, chain, args) = rules[0].iptables("ip4tun0");
+    assert_eq!((table, chain), ("mangle", "PREROUTING"));
+    assert_eq!(
+        args.join(" "),
+        "-j HMARK --hmark-tuple sport --hmark-mod 3 --hmark-offset 16 --hmark-rnd 4"
+    );
+    let (table, chain, args) = rules[1].iptables("ip4tun0");
+    assert_eq!((table, chain), ("mangle", "OUTPUT"));
+    assert_eq!(
+        args.join(" "),
+        "-o ip4tun0 -j HMARK --hmark-tuple sport --hmark-mod 3 --hmark-offset 16 --hmark-rnd 4"
+    );
+    let (table, chain, args) = rules[3].iptables("ip4tun0");
+    assert_eq!((table, chain), ("nat", "POSTROUTING"));
+    assert_eq!(
+        args.join(" "),
+        "-p tcp -o ip4tun0 -m mark --mark 16 -j SNAT --to 192.0.2.1:1000-1001"
+    );
+}
+
+#[test]
+fn renders_iptables_restore() {
+    assert_eq!(
+        nat::iptables_restore(&small_rules(), "ip4tun0"),
+        "\
+*mangle
+:v6plus-tun-prerouting - [0:0]
+:v6plus-tun-output - [0:0]
+:v6plus-tun-forward - [0:0]
+-A PREROUTING -j v6plus-tun-prerouting
+-A OUTPUT -j v6plus-tun-output
+-A FORWARD -j v6plus-tun-forward
+-A v6plus-tun-prerouting -j HMARK --hmark-tuple sport --hmark-mod 3 --hmark-offset 16 --hmark-rnd 4
+-A v6plus-tun-output -o ip4tun0 -j HMARK --hmark-tuple sport --hmark-mod 3 --hmark-offset 16 --hmark-rnd 4
+-A v6plus-tun-forward -o ip4tun0 -p tcp --tcp-flags SYN,RST SYN -m tcpmss --mss 1400:65495 -j TCPMSS --clamp-mss-to-pmtu
+COMMIT
+*nat
+-A POSTROUTING -p icmp -o ip4tun0 -m mark --mark 16 -j SNAT --to 192.0.2.1:1000-1001
+-A POSTROUTING -p tcp -o ip4tun0 -m mark --mark 16 -j SNAT --to 192.0.2.1:1000-1001
+-A POSTROUTING -p udp -o ip4tun0 -m mark --mark 16 -j SNAT --to 192.0.2.1:1000-1001
+-A POSTROUTING -p icmp -o ip4tun0 -m mark --mark 17 -j SNAT --to 192.0.2.1:2000-2003
+-A POSTROUTING -p tcp -o ip4tun0 -m mark --mark 17 -j SNAT --to 192.0.2.1:2000-2003
+-A POSTROUTING -p udp -o ip4tun0 -m mark --mark 17 -j SNAT --to 192.0.2.1:2000-2003
+-A POSTROUTING -p icmp -o ip4tun0 -m mark --mark 18 -j SNAT --to 192.0.2.1:2000-2003
+-A POSTROUTING -p tcp -o ip4tun0 -m mark --mark 18 -j SNAT --to 192.0.2.1:2000-2003
+-A POSTROUTING -p udp -o ip4tun0 -m mark --mark 18 -j SNAT --to 192.0.2.1:2000-2003
+-A PREROUTING ! -i ip4tun0 -p tcp --dport 53 ! -d 192.168.1.1 -j DNAT --to-destination 192.168.1.1
+-A PREROUTING ! -i ip4tun0 -p udp --dport 53 ! -d 192.168.1.1 -j DNAT --to-destination 192.168.1.1
+COMMIT
+"
+    );
+}
+
+#[test]
+fn renders_nft() {
+    let out = nat::nft(&small_rules(), "ip4tun0").unwrap();
+    assert!(out.starts_with("table ip v6plus_tun\ndelete table ip v6plus_tun\n"));
+    assert!(out.contains(
+        "    chain prerouting {
+        type filter hook prerouting priority mangle;
+        meta mark set jhash th sport mod 3 seed 0x4 offset 16
+    }"
+    ));
+    assert!(out.contains(
+        "        oifname \"ip4tun0\" meta l4proto udp meta mark 0x12 snat to 192.0.2.1:2000-2003\n"
+    ));
+    assert!(out.contains(
+        "        iifname != \"ip4tun0\" udp dport 53 ip daddr != 192.168.1.1 dnat to 192.168.1.1\n"
+    ));
+    assert!(out.ends_with("    }\n}\n"));
+
+    // Pins are iptables matches
+    let plan = SnatPlan::new(&ranges(), &[], &[parse_pin("-p tcp=1").unwrap()]).unwrap();
+    let err = nat::nft(&plan.rules(ADDR, None, true), "ip4tun0").unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "pin '-p tcp' is an iptables match, which can't be translated to nft"
+    );
+}
+
+#[test]
+fn parses_port_ranges() {
+    assert_eq!(parse_port_range("5060"), Ok((5060, 5060)));
+    assert_eq!(parse_port_range("7920-7923"), Ok((7920, 7923)));
+    assert_eq!(parse_port_range("10-5"), Err("10 is after 5".to_string()));
+    assert!(parse_port_range("65536")
+        .unwrap_err()
+        .starts_with("bad port '65536'"));
+    assert!(parse_port_range("1-x")
+        .unwrap_err()
+        .starts_with("bad port 'x'"));
+}
+
+// Just enough of iptables for install_iptables: the rules in each table's chains, in order
+#[derive(Clone, Debug, PartialEq)]
+struct FakeIptables {
+    chains: BTreeMap<(String, String), Vec<Vec<String>>>,
+}
+
+impl FakeIptables {
+    fn new() -> FakeIptables {
+        let builtin = [
+            ("nat", "PREROUTING"),
+            ("nat", "OUTPUT"),
+            ("nat", "POSTROUTING"),
+            ("mangle", "PREROUTING"),
+            ("mangle", "OUTPUT"),
+            ("mangle", "FORWARD"),
+            ("mangle", "POSTROUTING"),
+        ];
+        FakeIptables {
+            chains: builtin
+                .iter()
+                .map(|&(t, c)| ((t.to_string(), c.to_string()), vec![]))
+                .collect(),
+        }
+    }
+
+    fn chain(&mut self, table: &str, chain: &str) -> &mut Vec<Vec<String>> {
+        self.chains
+            .get_mut(&(table.to_string(), chain.to_string()))
+            .unwrap()
+    }
+
+    fn apply(&mut self, args: &[String]) -> Result<(), String> {
+        let [t, table, cmd, rest @ ..] = args else {
+            return Err(format!("unexpected {args:?}"));
+        };
+        assert_eq!(t, "-t");
+        if cmd == "-F" && rest.is_empty() {
+            for ((t, _), rules) in &mut self.chains {
+                if t == table {
+                    rules.clear();
+                }
+            }
+            return Ok(());
+        }
+        let (chain, rule) = rest.split_first().ok_or("no chain")?;
+        let key = (table.clone(), chain.clone());
+        if cmd == "-N" {
+            if self.chains.insert(key, vec![]).is_some() {
+                return Err("chain already exists".to_string());
+            }
+            return Ok(());
+        }
+        let rules = self.chains.get_mut(&key).ok_or("no such chain")?;
+        let found = rules.iter().position(|r| r == rule);
+        match cmd.as_str() {
+            "-F" => rules.clear(),
+            "-A" => rules.push(rule.to_vec()),
+            "-I" => {
+                let (n, rule) = rule.split_first().unwrap();
+                rules.insert(n.parse::<usize>().unwrap() - 1, rule.to_vec());
+            }
+            "-C" => {
+                found.ok_or("no such rule")?;
+            }
+            "-D" => {
+                rules.remove(found.ok_or("no such rule")?);
+            }
+            _ => return Err(format!("unexpected {cmd}")),
+        }
+        Ok(())
+    }
+}
+
+impl nat::Iptables for FakeIptables {
+    fn test(&mut self, args: &[String]) -> bool {
+        self.apply(args).is_ok()
+    }
+
+    fn run(&mut self, args: &[String]) -> anyhow::Result<()> {
+        self.apply(args).map_err(anyhow::Error::msg)
+    }
+}
+
+fn words(s: &str) -> Vec<String> {
+    s.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn reinstalling_replaces_the_rules() {
+    let mut iptables = FakeIptables::new();
+    // Another tool's rule, and two copies of an HMARK an earlier version put straight into
+    // PREROUTING
+    let other = words("-i lan0 -j MARK --set-mark 1");
+    let (_, _, stale) = small_rules()[0].iptables("ip4tun0");
+    iptables
+        .chain("mangle", "PREROUTING")
+        .extend([stale.clone(), other.clone(), stale]);
+
+    nat::install_iptables(&small_rules(), "ip4tun0", &mut iptables).unwrap();
+    let once = iptables.clone();
+    assert_eq!(
+        *iptables.chain("mangle", "PREROUTING"),
+        [words("-j v6plus-tun-prerouting"), other]
+    );
+    assert_eq!(iptables.chain("mangle", "v6plus-tun-prerouting").len(), 1);
+    assert_eq!(iptables.chain("mangle", "v6plus-tun-output").len(), 1);
+    assert_eq!(iptables.chain("mangle", "v6plus-tun-forward").len(), 1);
+    assert_eq!(iptables.chain("nat", "POSTROUTING").len(), 9);
+
+    // Reruns, with a different plan in between, leave nothing behind
+    let plan = SnatPlan::new(&ranges(), &[], &[]).unwrap();
+    nat::install_iptables(&plan.rules(ADDR, None, true), "ip4tun0", &mut iptables).unwrap();
+    assert_eq!(iptables.chain("nat", "POSTROUTING").len(), 15 * 3);
+    nat::install_iptables(&small_rules(), "ip4tun0", &mut iptables).unwrap();
+    assert_eq!(iptables, once);
+}
+
+#[test]
+fn restore_file_matches_setup() {
+    let mut installed = FakeIptables::new();
+    nat::install_iptables(&small_rules(), "ip4tun0", &mut installed).unwrap();
+
+    // iptables-restore empties every chain of a table it loads, creates the declared ones and
+    // appends the rest
+    let mut restored = FakeIptables::new();
+    let mut table = "";
+    for line in nat::iptables_restore(&small_rules(), "ip4tun0").lines() {
+        if let Some(t) = line.strip_prefix('*') {
+            table = t;
+            restored.apply(&words(&format!("-t {table} -F"))).unwrap();
+        } else if let Some(chain) = line.strip_prefix(':') {
+            let (chain, _) = chain.split_once(' ').unwrap();
+            restored
+                .apply(&words(&format!("-t {table} -N {chain}")))
+                .unwrap();
+        } else if line != "COMMIT" {
+            restored
+                .apply(&words(&format!("-t {table} {line}")))
+                .unwrap();
+        }
+    }
+    assert_eq!(restored, installed);
+}
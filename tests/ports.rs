@@ -0,0 +1,87 @@
+// Checks of PortSet against the plain range arithmetic it replaces
+
+use v6plus_tun::port_ranges;
+use v6plus_tun::ports::PortSet;
+
+#[test]
+fn psid_sets_agree_with_ranges() {
+    for psid in [0u16, 1, 0xef, 255] {
+        let ranges = port_ranges(psid, 4, 8);
+        let set = PortSet::for_psid(psid, 4, 8);
+        assert_eq!(set.ranges(), ranges.as_slice());
+        assert_eq!(set.len(), 240);
+        let ports: Vec<u16> = set.iter().collect();
+        assert_eq!(ports.len(), set.len());
+        for (n, &port) in ports.iter().enumerate() {
+            assert_eq!(set.nth_port(n), Some(port));
+        }
+        assert_eq!(set.nth_port(ports.len()), None);
+        for port in 0..=u16::MAX {
+            let range = ranges.iter().position(|&(s, e)| (s..=e).contains(&port));
+            assert_eq!(set.range_of(port), range, "psid {psid} port {port}");
+            assert_eq!(set.contains(port), range.is_some());
+        }
+    }
+}
+
+#[test]
+fn new_sorts_and_merges_overlaps_only() {
+    let set = PortSet::new([(20, 29), (0, 9), (5, 12), (10, 8), (13, 15)]);
+    assert_eq!(set.ranges(), &[(0, 12), (13, 15), (20, 29)]);
+    assert_eq!(set.len(), 26);
+    assert!(PortSet::new([]).is_empty());
+}
+
+#[test]
+fn exclude_splits() {
+    let mut set = PortSet::for_psid(0xef, 4, 8);
+    // The middle of the first range, all of the second and the end of the third
+    set.exclude((7925, 7930));
+    set.exclude((12016, 12031));
+    set.exclude((16120, 16200));
+    assert_eq!(
+        &set.ranges()[..4],
+        &[(7920, 7924), (7931, 7935), (16112, 16119), (20208, 20223)]
+    );
+    assert_eq!(set.len(), 240 - 6 - 16 - 8);
+    assert!(!set.contains(7925) && set.contains(7931) && !set.contains(12020));
+}
+
+#[test]
+fn contains_range_needs_one_range() {
+    let set = PortSet::new([(100, 109), (110, 119)]);
+    assert!(set.contains_range((100, 109)));
+    assert!(set.contains_range((112, 115)));
+    // Adjacent ranges aren't one, say for a forward that has to map to a single range
+    assert!(!set.contains_range((105, 112)));
+    assert!(!set.contains_range((115, 120)));
+    assert!(!set.contains_range((109, 100)));
+}
+
+#[test]
+fn remap_keeps_owned_and_moves_the_rest() {
+    let set = PortSet::new([(100, 109), (200, 203)]);
+    let wanted = [
+        (50, 53),   // not ours, moves to the first free 4-port span
+        (100, 101), // ours, stays, so nothing else is moved onto it
+        (300, 300),
+        (200, 209), // bigger than what's left in any one range
+        (105, 104),
+    ];
+    assert_eq!(
+        set.remap(&wanted),
+        [
+            Some((102, 105)),
+            Some((100, 101)),
+            Some((106, 106)),
+            None,
+            None
+        ]
+    );
+    // Earlier ones get first pick
+    assert_eq!(
+        set.remap(&[(0, 7), (1, 4)]),
+        [Some((100, 107)), Some((200, 203))]
+    );
+    assert_eq!(set.remap(&[(0, 3), (1, 4), (2, 5), (3, 6)])[3], None);
+}
@@ -0,0 +1,63 @@
+// Checks of what report-verified is willing to put in an issue
+
+use std::process::Command;
+
+// Runs report-verified for the address, with a rules file holding `rules` if it's given, giving
+// (success, stdout, stderr)
+fn report(addr: &str, rules: Option<&str>) -> (bool, String, String) {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_v6plus-tun"));
+    let path = std::env::temp_dir().join(format!(
+        "v6plus-tun-test-{}-{}.toml",
+        std::process::id(),
+        addr.replace(':', "-")
+    ));
+    if let Some(rules) = rules {
+        std::fs::write(&path, rules).unwrap();
+        cmd.arg("--rules").arg(&path);
+    }
+    let out = cmd.args(["report-verified", addr]).output().unwrap();
+    if rules.is_some() {
+        std::fs::remove_file(&path).unwrap();
+    }
+    (
+        out.status.success(),
+        String::from_utf8_lossy(&out.stdout).into_owned(),
+        String::from_utf8_lossy(&out.stderr).into_owned(),
+    )
+}
+
+#[test]
+fn reports_only_the_ipv6_prefix() {
+    let (ok, out, _) = report("240b:10:abcd:ef00::1", None);
+    assert!(ok);
+    assert!(out.contains("  Verified: 240b:10::/32\n"));
+    assert!(out.contains("The rule for 240b:10::/32 (confidence: "));
+    assert!(!out.contains("abcd"));
+    // Nothing IPv4, not even the rule's prefix
+    assert!(!out.contains("->"));
+}
+
+#[test]
+fn refuses_fixed_and_loaded_rules() {
+    let rules = r#"
+[[rule]]
+prefix = "2001:db8::/32"
+ipv4_prefix = "198.51.0.0/16"
+br = "2001:db8::1"
+
+[[rule]]
+prefix = "2001:db9:1234:5600::/56"
+ipv4_prefix = "192.0.2.7/32"
+br = "2001:db9::1"
+fixed = true
+"#;
+    let (ok, out, err) = report("2001:db8:1234:ef00::1", Some(rules));
+    assert!(!ok);
+    assert!(out.is_empty());
+    assert!(err.contains("the rule comes from --rules"));
+
+    let (ok, out, err) = report("2001:db9:1234:5600::1", Some(rules));
+    assert!(!ok);
+    assert!(out.is_empty());
+    assert!(err.contains("fixed IPv4 rules are for one customer's address"));
+}
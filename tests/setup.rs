@@ -0,0 +1,21 @@
+// Checks of setup-linux that fail before anything on the system is touched
+#![cfg(target_os = "linux")]
+
+use std::process::Command;
+
+#[test]
+fn nft_refuses_pins() {
+    // Pins are iptables matches, which the nft ruleset has no way to hold
+    let out = Command::new(env!("CARGO_BIN_EXE_v6plus-tun"))
+        .args([
+            "setup-linux",
+            "240b:10:abcd:ef00::1",
+            "--nft",
+            "--pin=-p tcp=1",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&out.stderr)
+        .contains("the argument '--nft' cannot be used with '--pin <PINS>'"));
+}
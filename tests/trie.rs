@@ -0,0 +1,70 @@
+// Checks of the prefix trie's longest-match, precedence and nearest-prefix behaviour
+
+use std::net::Ipv6Addr;
+
+use v6plus_tun::trie::PrefixTrie;
+
+fn addr(s: &str) -> Ipv6Addr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn longest_match_wins() {
+    let mut trie = PrefixTrie::new();
+    trie.insert(addr("2001:db8::"), 32, "/32");
+    trie.insert(addr("2001:db8:ff00::"), 40, "/40");
+    trie.insert(addr("2001:db8:ff00::"), 38, "/38");
+    assert_eq!(trie.lookup(addr("2001:db8:ff00::1")), Some((40, &"/40")));
+    assert_eq!(trie.lookup(addr("2001:db8:fc00::1")), Some((38, &"/38")));
+    assert_eq!(trie.lookup(addr("2001:db8:1::1")), Some((32, &"/32")));
+    assert_eq!(trie.lookup(addr("2001:db9::1")), None);
+}
+
+#[test]
+fn first_insert_wins() {
+    let mut trie = PrefixTrie::new();
+    assert!(trie.insert(addr("240b:10::"), 32, "loaded"));
+    // Bits past the length don't make it a different prefix
+    assert!(!trie.insert(addr("240b:10:ffff::"), 32, "built-in"));
+    assert_eq!(trie.lookup(addr("240b:10::1")), Some((32, &"loaded")));
+}
+
+#[test]
+fn default_and_host_routes() {
+    let mut trie = PrefixTrie::new();
+    trie.insert(Ipv6Addr::UNSPECIFIED, 0, "default");
+    trie.insert(addr("2001:db8::1"), 128, "host");
+    assert_eq!(trie.lookup(addr("2001:db8::1")), Some((128, &"host")));
+    assert_eq!(trie.lookup(addr("2001:db8::2")), Some((0, &"default")));
+}
+
+// Every prefix length, checked against the obvious linear scan
+#[test]
+fn matches_linear_scan() {
+    let base = addr("2404:7a80:1234:5678:9abc:def0:1234:5678");
+    let mut trie = PrefixTrie::new();
+    for len in (0..=128).step_by(3) {
+        trie.insert(base, len, len);
+    }
+    for flip in 0..128u32 {
+        let probe = Ipv6Addr::from(u128::from(base) ^ (1 << (127 - flip)));
+        let expect = (0..=128)
+            .step_by(3)
+            .filter(|&len| len <= flip as u8)
+            .max()
+            .unwrap();
+        assert_eq!(trie.lookup(probe), Some((expect, &expect)));
+    }
+}
+
+#[test]
+fn nearest_prefix() {
+    let mut trie = PrefixTrie::new();
+    trie.insert(addr("240b:10::"), 32, "240b:10");
+    trie.insert(addr("240b:250::"), 32, "240b:250");
+    trie.insert(addr("2404:7a80::"), 32, "2404:7a80");
+    assert_eq!(trie.nearest(addr("240b:14::1")), Some(&"240b:10"));
+    assert_eq!(trie.nearest(addr("240b:254::1")), Some(&"240b:250"));
+    assert_eq!(trie.nearest(addr("2404:7a90::1")), Some(&"2404:7a80"));
+    assert_eq!(PrefixTrie::<()>::new().nearest(addr("::1")), None);
+}